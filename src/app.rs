@@ -1,18 +1,97 @@
 extern crate chrono_tz;
 extern crate clap;
+extern crate rename_by_exif;
+extern crate toml;
+use super::config::{OptionKind, Settings};
 use chrono_tz::Tz;
-use clap::{App, Arg, ArgMatches};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use rename_by_exif::collision::{CollisionResolver, SerialFormat, DEFAULT_SERIAL_FORMAT};
+use rename_by_exif::datesource::{parse_date_sources, DateSource, DEFAULT_DATE_SOURCES};
+use rename_by_exif::localtime::DstPolicy;
+use rename_by_exif::skew::{ClockShift, Reference};
+use rename_by_exif::template::{check_strftime, FilenameTemplate, DEFAULT_FILENAME_FORMAT};
+use rename_by_exif::walk::WalkOptions;
 use std::collections::HashSet;
+use std::env;
+use std::ffi::OsString;
 use std::process;
+use toml::Value;
+
+/// The options which can be set in the config files: the name, the long name and the kind.
+const CONFIG_OPTIONS: &[(&str, &str, OptionKind)] = &[
+    ("copy", "copy", OptionKind::Flag),
+    ("subdir-by-date", "subdir-by-date", OptionKind::Flag),
+    ("dirname-format", "dirname-format", OptionKind::Value),
+    ("filename-format", "filename-format", OptionKind::Value),
+    ("extensions", "ext", OptionKind::Values),
+    ("recursive", "recursive", OptionKind::Flag),
+    ("max-depth", "max-depth", OptionKind::Value),
+    ("follow-links", "follow-links", OptionKind::Flag),
+    ("skip-hidden", "skip-hidden", OptionKind::Flag),
+    ("collision", "collision", OptionKind::Value),
+    ("serial-format", "serial-format", OptionKind::Value),
+    ("date-sources", "date-sources", OptionKind::Value),
+    ("from-tz", "from-tz", OptionKind::Value),
+    ("camera-profiles", "camera-profiles", OptionKind::Path),
+    ("shift", "shift", OptionKind::Values),
+    ("reference", "reference", OptionKind::Values),
+    ("shift-from-gps", "shift-from-gps", OptionKind::Flag),
+    ("tz-from-gps", "tz-from-gps", OptionKind::Flag),
+    ("dst-policy", "dst-policy", OptionKind::Value),
+    ("to-tz", "to-tz", OptionKind::Value),
+    ("fail-fast", "fail-fast", OptionKind::Flag),
+    ("verbose", "verbose", OptionKind::Flag),
+    ("dry-run", "dry-run", OptionKind::Flag),
+];
+
+/// The options which turn off a flag of the config files: the name and the flag.
+const NEGATED_FLAGS: &[(&str, &str)] = &[
+    ("no-copy", "copy"),
+    ("no-subdir-by-date", "subdir-by-date"),
+    ("no-recursive", "recursive"),
+    ("no-follow-links", "follow-links"),
+    ("no-skip-hidden", "skip-hidden"),
+    ("no-shift-from-gps", "shift-from-gps"),
+    ("no-tz-from-gps", "tz-from-gps"),
+    ("no-fail-fast", "fail-fast"),
+    ("no-verbose", "verbose"),
+    ("no-dry-run", "dry-run"),
+];
 
 pub fn app<'a, 'b>() -> App<'a, 'b> {
-    return App::new("Rename by EXIF")
+    let app = App::new("Rename by EXIF")
         .version("0.1.0")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .after_help(
+            "EXIT STATUS:\n    \
+             0    All files were handled\n    \
+             1    A fatal error stopped the run\n    \
+             2    Some files were skipped because of errors\n\n\
+             CONFIGURATION:\n    \
+             The options can also be set in TOML files, with the long option names as keys.\n    \
+             /etc/rename-by-exif/config.toml, ~/.config/rename-by-exif/config.toml and\n    \
+             .rename-by-exif.toml in the current or a parent directory are read in this order,\n    \
+             and the command line overrides them. [profile.NAME] tables are applied with --profile.\n    \
+             A flag set in the files is turned off with --no-FLAG, e.g. --no-copy. Relative paths\n    \
+             in the files are relative to the file.",
+        )
+        .subcommand(
+            SubCommand::with_name("undo")
+                .about("Reverts a run recorded in a journal file")
+                .arg(
+                    Arg::with_name("journal")
+                        .help("Journal file written under DESTINATION/.rename-by-exif")
+                        .value_name("JOURNAL")
+                        .required(true)
+                        .index(1),
+                ),
+        )
         .arg(
             Arg::with_name("destination")
                 .help("Rename destination directory")
                 .value_name("DESTINATION")
-                .required(true)
+                .required_unless("print-config")
                 .empty_values(false)
                 .index(1),
         )
@@ -20,7 +99,7 @@ pub fn app<'a, 'b>() -> App<'a, 'b> {
             Arg::with_name("sources")
                 .help("Rename source directories/filenames")
                 .value_name("SOURCES")
-                .required(true)
+                .required_unless("print-config")
                 .empty_values(false)
                 .multiple(true),
         )
@@ -38,17 +117,24 @@ pub fn app<'a, 'b>() -> App<'a, 'b> {
         )
         .arg(
             Arg::with_name("dirname-format")
-                .help("Specifies the format of the directory name")
+                .help("Specifies the strftime format of the sub directory name (used with --subdir-by-date)")
                 .display_order(0)
                 .long("dirname-format")
-                .default_value("%Y%m%d-%H%M%S"),
+                .default_value("%Y%m%d-%H%M%S")
+                .validator(|s| check_strftime(&s).map(|_| ())),
         )
         .arg(
             Arg::with_name("filename-format")
-                .help("Specifies the format of the filename. (case insensitive and no dot)")
+                .help(
+                    "Specifies the format of the filename. strftime fields and placeholders \
+                     {make}, {model}, {lens}, {stem}, {ext}, {subsec}, {seq[:N]} and {hash[:N]} \
+                     are available. The extension is appended unless {ext} is used. \
+                     Use %.3f or {subsec} to tell apart burst shots within a second.",
+                )
                 .display_order(1)
                 .long("filename-format")
-                .takes_value(true),
+                .default_value(DEFAULT_FILENAME_FORMAT)
+                .validator(|s| s.parse::<FilenameTemplate>().map(|_| ())),
         )
         .arg(
             Arg::with_name("extensions")
@@ -61,18 +147,70 @@ pub fn app<'a, 'b>() -> App<'a, 'b> {
         )
         .arg(
             Arg::with_name("recursive")
-                .help("Recursive (FIXME)")
+                .help("Walks source directories recursively")
                 .long("recursive")
                 .short("r"),
         )
+        .arg(
+            Arg::with_name("max-depth")
+                .help("Limits the depth of --recursive")
+                .display_order(3)
+                .long("max-depth")
+                .takes_value(true)
+                .requires("recursive")
+                .validator(|s| {
+                    s.parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("must be a non-negative integer"))
+                }),
+        )
+        .arg(
+            Arg::with_name("follow-links")
+                .help("Follows symbolic links while walking source directories")
+                .long("follow-links")
+                .short("L"),
+        )
+        .arg(
+            Arg::with_name("skip-hidden")
+                .help("Skips hidden files and directories while walking source directories")
+                .long("skip-hidden"),
+        )
         .arg(
             Arg::with_name("collision")
-                .help("How to handle filename collision (FIXME)")
+                .help(
+                    "How to handle filename collision. \
+                     `overwrite` replaces files from before the run, which are kept for `undo`, \
+                     and numbers the \
+                     files of the run which share a name like `serial`. \
+                     `abort` stops before any file is changed. \
+                     `dedupe` skips byte-identical files and falls back to `serial` otherwise.",
+                )
                 .display_order(4)
                 .long("collision")
-                .possible_values(&["overwrite", "skip", "serial", "abort"])
+                .possible_values(&["overwrite", "skip", "serial", "abort", "dedupe"])
                 .default_value("overwrite"),
         )
+        .arg(
+            Arg::with_name("serial-format")
+                .help("Specifies the counter format of the `serial` collision strategy. {n:N} pads the counter to N digits.")
+                .display_order(4)
+                .long("serial-format")
+                .default_value(DEFAULT_SERIAL_FORMAT)
+                .allow_hyphen_values(true)
+                .validator(|s| s.parse::<SerialFormat>().map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("date-sources")
+                .help(
+                    "Comma separated list of the date sources to try in order: \
+                     original, digitized, datetime, gps, xmp, filename and mtime. \
+                     The creation date of a video counts as original",
+                )
+                .display_order(5)
+                .long("date-sources")
+                .default_value(DEFAULT_DATE_SOURCES)
+                .validator(|s| parse_date_sources(&s).map(|_| ())),
+        )
         .arg(
             Arg::with_name("from-tz")
                 .help("FIXME")
@@ -81,6 +219,76 @@ pub fn app<'a, 'b>() -> App<'a, 'b> {
                 .takes_value(true)
                 .empty_values(false),
         )
+        .arg(
+            Arg::with_name("camera-profiles")
+                .help(
+                    "Reads a TOML file which maps camera bodies by serial, make and model \
+                     to the time zone and the offset of their clocks",
+                )
+                .display_order(6)
+                .long("camera-profiles")
+                .value_name("FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("shift")
+                .help(
+                    "Corrects the camera clock by [+-]HH:MM[:SS] before renaming. \
+                     Prefix BODY= to apply it to a body only, where BODY is a serial number, \
+                     a model, or a make and model. Can be given multiple times.",
+                )
+                .display_order(6)
+                .long("shift")
+                .value_name("[BODY=]OFFSET")
+                .multiple(true)
+                .number_of_values(1)
+                .allow_hyphen_values(true)
+                .validator(|s| s.parse::<ClockShift>().map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("reference")
+                .help(
+                    "Measures the clock of the body from a photo of a clock and the time it \
+                     shows, e.g. IMG_0001.JPG=\"2019-10-01 12:34:56\". Can be given multiple times.",
+                )
+                .display_order(6)
+                .long("reference")
+                .value_name("PHOTO=TIME")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| s.parse::<Reference>().map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("shift-from-gps")
+                .help(
+                    "Measures the clock of each body from the files which have \
+                     both a GPS time and a DateTimeOriginal",
+                )
+                .display_order(6)
+                .long("shift-from-gps"),
+        )
+        .arg(
+            Arg::with_name("tz-from-gps")
+                .help(
+                    "Looks up the time zone of local times from the GPS position \
+                     in a bundled offline dataset, before --from-tz",
+                )
+                .display_order(5)
+                .long("tz-from-gps"),
+        )
+        .arg(
+            Arg::with_name("dst-policy")
+                .help(
+                    "How to resolve local times which are ambiguous or nonexistent because of DST. \
+                     `earliest` and `latest` pick one of the repeated hour and reject a gap, \
+                     `reject` rejects both, and `shift-forward` picks the earlier one and moves \
+                     a time in a gap forward.",
+                )
+                .display_order(5)
+                .long("dst-policy")
+                .possible_values(&["earliest", "latest", "reject", "shift-forward"])
+                .default_value("earliest"),
+        )
         .arg(
             Arg::with_name("to-tz")
                 .help("FIXME")
@@ -89,6 +297,11 @@ pub fn app<'a, 'b>() -> App<'a, 'b> {
                 .takes_value(true)
                 .empty_values(false),
         )
+        .arg(
+            Arg::with_name("fail-fast")
+                .help("Stops at the first file which cannot be handled")
+                .long("fail-fast"),
+        )
         .arg(
             Arg::with_name("verbose")
                 .help("Verbose outut (FIXME)")
@@ -97,10 +310,89 @@ pub fn app<'a, 'b>() -> App<'a, 'b> {
         )
         .arg(
             Arg::with_name("dry-run")
-                .help("Dry run (FIXME)")
+                .help("Prints the planned operations without touching the filesystem")
                 .long("dry-run")
                 .short("n"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .help("Applies the [profile.NAME] tables of the config files")
+                .long("profile")
+                .value_name("NAME")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("print-config")
+                .help("Prints the resolved settings and where they come from")
+                .long("print-config"),
         );
+    NEGATED_FLAGS.iter().fold(app, |app, &(name, flag)| {
+        app.arg(
+            Arg::with_name(name)
+                .long(name)
+                .hidden(true)
+                .overrides_with(flag),
+        )
+    })
+}
+
+/// Parses the command line on top of the settings of the config files.
+pub fn get_matches() -> ArgMatches<'static> {
+    let args: Vec<OsString> = env::args_os().collect();
+    let cli = app().get_matches_from(&args);
+    if cli.subcommand_name().is_some() {
+        return cli;
+    }
+    let settings = match Settings::load(CONFIG_OPTIONS, cli.value_of("profile")) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Failed to read the config: {}", e);
+            process::exit(1);
+        }
+    };
+    // A negated flag counts as given, so that the config files cannot set it.
+    let given = |key: &str| {
+        cli.occurrences_of(key) > 0
+            || NEGATED_FLAGS
+                .iter()
+                .any(|&(name, flag)| flag == key && cli.occurrences_of(name) > 0)
+    };
+    // Put the settings before `--`, which ends the options.
+    let end = args.iter().position(|a| a == "--").unwrap_or(args.len());
+    let mut merged = args[..end].to_vec();
+    merged.extend(settings.to_args(given));
+    merged.extend_from_slice(&args[end..]);
+    let matches = app().get_matches_from(merged);
+    if matches.is_present("print-config") {
+        print_settings(&matches, &settings, given);
+        process::exit(0);
+    }
+    matches
+}
+
+fn print_settings<F>(matches: &ArgMatches, settings: &Settings, given: F)
+where
+    F: Fn(&str) -> bool,
+{
+    for &(key, long, kind) in CONFIG_OPTIONS.iter() {
+        let string = |v: &str| Value::String(v.to_string());
+        let value = match kind {
+            OptionKind::Flag => Some(Value::Boolean(matches.is_present(key))),
+            OptionKind::Value | OptionKind::Path => matches.value_of(key).map(string),
+            OptionKind::Values => matches
+                .values_of(key)
+                .map(|values| Value::Array(values.map(string).collect())),
+        };
+        let origin = match settings.get(key) {
+            _ if given(key) => String::from("command line"),
+            Some(setting) => setting.origin.clone(),
+            None => String::from("default"),
+        };
+        match value {
+            Some(value) => println!("{} = {}  # {}", long, value, origin),
+            None => println!("# {} is not set", long),
+        }
+    }
 }
 
 pub fn get_timezones(matches: &ArgMatches) -> (Option<Tz>, Option<Tz>) {
@@ -108,20 +400,52 @@ pub fn get_timezones(matches: &ArgMatches) -> (Option<Tz>, Option<Tz>) {
         None => Ok(None),
         Some(t) => match t.parse() {
             Ok(tz) => Ok(Some(tz)),
-            Err(e) => Err(eprintln!("Failed to parse from-tz: {}", e)),
+            Err(_) => {
+                eprintln!("Failed to parse from-tz: Unknown time zone {}", t);
+                Err(())
+            }
         },
     };
     let to_tz: Result<Option<Tz>, ()> = match matches.value_of("to-tz") {
         None => Ok(None),
         Some(t) => match t.parse() {
             Ok(tz) => Ok(Some(tz)),
-            Err(e) => Err(eprintln!("Failed to parse to-tz: {}", e)),
+            Err(_) => {
+                eprintln!("Failed to parse to-tz: Unknown time zone {}", t);
+                Err(())
+            }
         },
     };
-    return match (from_tz, to_tz) {
+    match (from_tz, to_tz) {
         (Ok(f), Ok(t)) => (f, t),
         _ => process::exit(1),
-    };
+    }
+}
+
+pub fn get_date_sources(matches: &ArgMatches) -> Vec<DateSource> {
+    // The value has already been verified by clap.
+    parse_date_sources(matches.value_of("date-sources").unwrap()).unwrap()
+}
+
+pub fn get_clock_shifts(matches: &ArgMatches) -> Vec<ClockShift> {
+    // The values have already been verified by clap.
+    matches
+        .values_of("shift")
+        .map(|values| values.map(|s| s.parse().unwrap()).collect())
+        .unwrap_or_default()
+}
+
+pub fn get_references(matches: &ArgMatches) -> Vec<Reference> {
+    // The values have already been verified by clap.
+    matches
+        .values_of("reference")
+        .map(|values| values.map(|s| s.parse().unwrap()).collect())
+        .unwrap_or_default()
+}
+
+pub fn get_dst_policy(matches: &ArgMatches) -> DstPolicy {
+    // The value has already been verified by clap.
+    matches.value_of("dst-policy").unwrap().parse().unwrap()
 }
 
 pub fn get_extension_filter(matches: &ArgMatches) -> Box<dyn Fn(&String) -> bool> {
@@ -135,5 +459,31 @@ pub fn get_extension_filter(matches: &ArgMatches) -> Box<dyn Fn(&String) -> bool
         .map(|ext| ext.to_lowercase())
         .collect();
 
-    return Box::new(move |lcext: &String| extensions.contains(lcext));
+    Box::new(move |lcext: &String| extensions.contains(lcext))
+}
+
+pub fn get_filename_template(matches: &ArgMatches) -> FilenameTemplate {
+    // The format has already been verified by the validator.
+    matches
+        .value_of("filename-format")
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+pub fn get_collision_resolver(matches: &ArgMatches) -> CollisionResolver {
+    // Both values have already been verified by clap.
+    let strategy = matches.value_of("collision").unwrap().parse().unwrap();
+    let serial_format = matches.value_of("serial-format").unwrap().parse().unwrap();
+    CollisionResolver::new(strategy, serial_format)
+}
+
+pub fn get_walk_options(matches: &ArgMatches) -> WalkOptions {
+    let mut options = WalkOptions::default();
+    options.recursive = matches.is_present("recursive");
+    // The depth has already been verified by the validator.
+    options.max_depth = matches.value_of("max-depth").map(|s| s.parse().unwrap());
+    options.follow_links = matches.is_present("follow-links");
+    options.skip_hidden = matches.is_present("skip-hidden");
+    options
 }
@@ -0,0 +1,205 @@
+extern crate chrono;
+extern crate chrono_tz;
+extern crate serde;
+extern crate toml;
+
+use super::skew::parse_offset;
+use chrono::Duration;
+use chrono_tz::Tz;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// A camera body, identified by `Make`, `Model` and `BodySerialNumber`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct CameraId {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+}
+
+impl CameraId {
+    /// Returns true if the key is the serial number, the model, or the make and model.
+    pub fn matches(&self, key: &str) -> bool {
+        let eq =
+            |value: &Option<String>| value.as_ref().is_some_and(|v| v.eq_ignore_ascii_case(key));
+        let make_model = match (&self.make, &self.model) {
+            (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+            _ => None,
+        };
+        eq(&self.serial) || eq(&self.model) || eq(&make_model)
+    }
+}
+
+impl fmt::Display for CameraId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name: Vec<&str> = [&self.make, &self.model]
+            .iter()
+            .filter_map(|v| v.as_deref())
+            .collect();
+        if name.is_empty() {
+            write!(f, "unknown camera")?;
+        } else {
+            write!(f, "{}", name.join(" "))?;
+        }
+        match self.serial {
+            Some(ref serial) => write!(f, " #{}", serial),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The clock setting of a camera body.
+#[derive(Debug, Clone, Default)]
+pub struct CameraProfile {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    /// The time zone which the clock is set to.
+    pub timezone: Option<Tz>,
+    /// The correction of the clock.
+    pub offset: Option<Duration>,
+}
+
+impl CameraProfile {
+    /// Returns how specific the match is, or `None` if any key differs.
+    ///
+    /// A serial number outweighs a model, which outweighs a make.
+    fn specificity(&self, body: &CameraId) -> Option<usize> {
+        let keys = [
+            (&self.make, &body.make, 1),
+            (&self.model, &body.model, 2),
+            (&self.serial, &body.serial, 4),
+        ];
+        let mut specificity = 0;
+        for (key, value, weight) in keys.iter() {
+            if let Some(key) = key {
+                match value {
+                    Some(value) if value.eq_ignore_ascii_case(key) => specificity += weight,
+                    _ => return None,
+                }
+            }
+        }
+        Some(specificity)
+    }
+}
+
+/// The camera profiles of a config file like:
+///
+/// ```toml
+/// [[camera]]
+/// serial = "93012345"
+/// timezone = "Europe/Berlin"
+///
+/// [[camera]]
+/// make = "SIGMA"
+/// model = "sd Quattro H"
+/// timezone = "Asia/Tokyo"
+/// offset = "+00:01:30"
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CameraProfiles {
+    profiles: Vec<CameraProfile>,
+}
+
+/// An error reading the camera profiles.
+#[derive(Debug)]
+pub enum ProfileError {
+    Io(io::Error),
+    /// The file is not TOML, or has unknown fields.
+    Toml(toml::de::Error),
+    /// The profile of the n-th camera, counted from 1, is invalid.
+    Invalid(usize, String),
+}
+
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProfileError::Io(ref err) => err.fmt(f),
+            ProfileError::Toml(ref err) => err.fmt(f),
+            ProfileError::Invalid(n, ref reason) => write!(f, "camera #{}: {}", n, reason),
+        }
+    }
+}
+
+impl std::error::Error for ProfileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            ProfileError::Io(ref err) => Some(err),
+            ProfileError::Toml(ref err) => Some(err),
+            ProfileError::Invalid(..) => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfilesFile {
+    #[serde(default)]
+    camera: Vec<ProfileEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileEntry {
+    make: Option<String>,
+    model: Option<String>,
+    serial: Option<String>,
+    timezone: Option<String>,
+    offset: Option<String>,
+}
+
+impl CameraProfiles {
+    pub fn load(path: &Path) -> Result<Self, ProfileError> {
+        let text = fs::read_to_string(path).map_err(ProfileError::Io)?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, ProfileError> {
+        let file: ProfilesFile = toml::from_str(text).map_err(ProfileError::Toml)?;
+        let profiles = file
+            .camera
+            .into_iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                let invalid = |reason: String| ProfileError::Invalid(i + 1, reason);
+                let profile = CameraProfile {
+                    timezone: entry
+                        .timezone
+                        .map(|tz| {
+                            tz.parse::<Tz>()
+                                .map_err(|_| invalid(format!("Unknown time zone {}", tz)))
+                        })
+                        .transpose()?,
+                    offset: entry
+                        .offset
+                        .map(|o| parse_offset(&o).map_err(invalid))
+                        .transpose()?,
+                    make: entry.make,
+                    model: entry.model,
+                    serial: entry.serial,
+                };
+                if profile.make.is_none() && profile.model.is_none() && profile.serial.is_none() {
+                    return Err(invalid(String::from("no make, model or serial")));
+                }
+                Ok(profile)
+            })
+            .collect::<Result<_, ProfileError>>()?;
+        Ok(CameraProfiles { profiles })
+    }
+
+    /// Returns the most specific profile of the body, the first one on a tie.
+    pub fn find(&self, body: &CameraId) -> Option<&CameraProfile> {
+        let mut best: Option<(usize, &CameraProfile)> = None;
+        for profile in self.profiles.iter() {
+            if let Some(n) = profile.specificity(body) {
+                if best.is_none_or(|(m, _)| n > m) {
+                    best = Some((n, profile));
+                }
+            }
+        }
+        best.map(|(_, profile)| profile)
+    }
+}
@@ -0,0 +1,362 @@
+use super::hash::sha256_file;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+pub const DEFAULT_SERIAL_FORMAT: &str = "_{n}";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Collision {
+    Overwrite,
+    Skip,
+    Serial,
+    Abort,
+    Dedupe,
+}
+
+impl FromStr for Collision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overwrite" => Ok(Collision::Overwrite),
+            "skip" => Ok(Collision::Skip),
+            "serial" => Ok(Collision::Serial),
+            "abort" => Ok(Collision::Abort),
+            "dedupe" => Ok(Collision::Dedupe),
+            _ => Err(format!("Unknown collision strategy: {}", s)),
+        }
+    }
+}
+
+/// The counter format of the `serial` strategy, e.g. `_{n}` or `-{n:3}`.
+#[derive(Debug)]
+pub struct SerialFormat {
+    prefix: String,
+    suffix: String,
+    width: usize,
+}
+
+impl SerialFormat {
+    pub fn format(&self, n: usize) -> String {
+        format!(
+            "{}{:0width$}{}",
+            self.prefix,
+            n,
+            self.suffix,
+            width = self.width
+        )
+    }
+}
+
+impl FromStr for SerialFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let start = s
+            .find("{n")
+            .ok_or_else(|| String::from("The serial format must contain {n}"))?;
+        let end = start
+            + s[start..]
+                .find('}')
+                .ok_or_else(|| String::from("Unclosed {n in the serial format"))?;
+        let width = match &s[start + 2..end] {
+            "" => 1,
+            spec if spec.starts_with(':') => spec[1..]
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid width in the serial format: {}", s))?,
+            _ => return Err(format!("Invalid serial format: {}", s)),
+        };
+        let prefix = String::from(&s[..start]);
+        let suffix = String::from(&s[end + 1..]);
+        if prefix.contains(&['/', '\\'][..]) || suffix.contains(&['/', '\\'][..]) {
+            return Err(String::from(
+                "The serial format cannot contain path separators",
+            ));
+        }
+        Ok(SerialFormat {
+            prefix,
+            suffix,
+            width,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Resolution {
+    Target(PathBuf),
+    /// The target is a file from before the run, which the source replaces.
+    Replace(PathBuf),
+    Skip,
+    Duplicate(PathBuf),
+    /// The source is already at the target.
+    InPlace,
+}
+
+#[derive(Debug)]
+pub enum CollisionError {
+    /// The `abort` strategy found a collision.
+    Collided {
+        source: PathBuf,
+        target: PathBuf,
+    },
+    Io(io::Error),
+}
+
+impl fmt::Display for CollisionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CollisionError::Collided {
+                ref source,
+                ref target,
+            } => write!(f, "{} collides with {}", source.display(), target.display()),
+            CollisionError::Io(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl From<io::Error> for CollisionError {
+    fn from(err: io::Error) -> CollisionError {
+        CollisionError::Io(err)
+    }
+}
+
+/// Resolves target filename collisions against both the files on disk and
+/// the targets already claimed by the preceding sources in the same run.
+pub struct CollisionResolver {
+    strategy: Collision,
+    serial_format: SerialFormat,
+    /// Maps each claimed target to its source.
+    claimed: HashMap<PathBuf, PathBuf>,
+}
+
+impl CollisionResolver {
+    pub fn new(strategy: Collision, serial_format: SerialFormat) -> Self {
+        CollisionResolver {
+            strategy,
+            serial_format,
+            claimed: HashMap::new(),
+        }
+    }
+
+    pub fn resolve(
+        &mut self,
+        source: &Path,
+        target: PathBuf,
+    ) -> Result<Resolution, CollisionError> {
+        if is_same_file(source, &target) {
+            return Ok(Resolution::InPlace);
+        }
+        if !self.collides(&target) {
+            self.claim(source, &target);
+            return Ok(Resolution::Target(target));
+        }
+        match self.strategy {
+            // Only a file from before the run is replaced. A target which a
+            // preceding source has claimed, e.g. by a burst shot in the same
+            // second, takes a serial name so that neither photo is lost.
+            Collision::Overwrite if !self.claimed.contains_key(&target) => {
+                self.claim(source, &target);
+                Ok(Resolution::Replace(target))
+            }
+            Collision::Overwrite | Collision::Serial => {
+                let target = self.next_serial(&target);
+                self.claim(source, &target);
+                Ok(Resolution::Target(target))
+            }
+            Collision::Skip => Ok(Resolution::Skip),
+            Collision::Dedupe => self.resolve_by_content(source, target),
+            Collision::Abort => Err(CollisionError::Collided {
+                source: source.to_path_buf(),
+                target,
+            }),
+        }
+    }
+
+    /// Skips the source if any of the target or its serial variants has the same content,
+    /// otherwise takes the first free serial name.
+    fn resolve_by_content(
+        &mut self,
+        source: &Path,
+        target: PathBuf,
+    ) -> Result<Resolution, CollisionError> {
+        let source_hash = sha256_file(source)?;
+        let mut free = None;
+        for candidate in self.candidates(&target) {
+            if !self.collides(&candidate) {
+                free = Some(candidate);
+                break;
+            }
+            // Compare with the source which is going to be there if it is claimed in this run.
+            let existing = self.claimed.get(&candidate).unwrap_or(&candidate);
+            let existing_hash = sha256_file(existing)?;
+            if existing_hash == source_hash {
+                return Ok(Resolution::Duplicate(candidate));
+            }
+        }
+        let target = free.unwrap();
+        self.claim(source, &target);
+        Ok(Resolution::Target(target))
+    }
+
+    fn claim(&mut self, source: &Path, target: &Path) {
+        self.claimed
+            .insert(target.to_path_buf(), source.to_path_buf());
+    }
+
+    fn collides(&self, target: &Path) -> bool {
+        self.claimed.contains_key(target) || target.exists()
+    }
+
+    fn next_serial(&self, target: &Path) -> PathBuf {
+        self.candidates(target)
+            .find(|candidate| !self.collides(candidate))
+            .unwrap()
+    }
+
+    /// Yields the target itself followed by its serial variants.
+    fn candidates<'a>(&'a self, target: &'a Path) -> impl Iterator<Item = PathBuf> + 'a {
+        let stem = target.file_stem().unwrap_or_default().to_string_lossy();
+        let ext = target.extension().map(|ext| ext.to_string_lossy());
+        let serials = (1..).map(move |n| {
+            let filename = match ext {
+                Some(ref ext) => format!("{}{}.{}", stem, self.serial_format.format(n), ext),
+                None => format!("{}{}", stem, self.serial_format.format(n)),
+            };
+            target.with_file_name(filename)
+        });
+        std::iter::once(target.to_path_buf()).chain(serials)
+    }
+}
+
+/// Returns true if both paths are the same existing file.
+fn is_same_file(source: &Path, target: &Path) -> bool {
+    match (fs::canonicalize(source), fs::canonicalize(target)) {
+        (Ok(source), Ok(target)) => source == target,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    /// Creates an empty directory for a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rename-by-exif-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: &Path, contents: &str) -> PathBuf {
+        fs::write(path, contents).unwrap();
+        path.to_path_buf()
+    }
+
+    fn resolver(strategy: Collision) -> CollisionResolver {
+        CollisionResolver::new(strategy, DEFAULT_SERIAL_FORMAT.parse().unwrap())
+    }
+
+    #[test]
+    fn overwrite_replaces_a_file_from_before_the_run() {
+        let dir = test_dir("overwrite-disk");
+        let a = write(&dir.join("a.jpg"), "a");
+        let existing = write(&dir.join("t.jpg"), "old");
+        let mut resolver = resolver(Collision::Overwrite);
+        assert_eq!(
+            resolver.resolve(&a, existing.clone()).unwrap(),
+            Resolution::Replace(existing)
+        );
+    }
+
+    #[test]
+    fn overwrite_takes_a_serial_name_within_the_run() {
+        let dir = test_dir("overwrite-run");
+        let a = write(&dir.join("a.jpg"), "a");
+        let b = write(&dir.join("b.jpg"), "b");
+        let target = dir.join("t.jpg");
+        let mut resolver = resolver(Collision::Overwrite);
+        assert_eq!(
+            resolver.resolve(&a, target.clone()).unwrap(),
+            Resolution::Target(target.clone())
+        );
+        assert_eq!(
+            resolver.resolve(&b, target).unwrap(),
+            Resolution::Target(dir.join("t_1.jpg"))
+        );
+    }
+
+    #[test]
+    fn skip_leaves_the_source() {
+        let dir = test_dir("skip");
+        let a = write(&dir.join("a.jpg"), "a");
+        let existing = write(&dir.join("t.jpg"), "old");
+        let mut resolver = resolver(Collision::Skip);
+        assert_eq!(resolver.resolve(&a, existing).unwrap(), Resolution::Skip);
+    }
+
+    #[test]
+    fn serial_takes_the_first_free_name() {
+        let dir = test_dir("serial");
+        let a = write(&dir.join("a.jpg"), "a");
+        let b = write(&dir.join("b.jpg"), "b");
+        let existing = write(&dir.join("t.jpg"), "old");
+        write(&dir.join("t-01.jpg"), "old");
+        let mut resolver = CollisionResolver::new(Collision::Serial, "-{n:2}".parse().unwrap());
+        assert_eq!(
+            resolver.resolve(&a, existing.clone()).unwrap(),
+            Resolution::Target(dir.join("t-02.jpg"))
+        );
+        assert_eq!(
+            resolver.resolve(&b, existing).unwrap(),
+            Resolution::Target(dir.join("t-03.jpg"))
+        );
+    }
+
+    #[test]
+    fn abort_reports_the_collision() {
+        let dir = test_dir("abort");
+        let a = write(&dir.join("a.jpg"), "a");
+        let existing = write(&dir.join("t.jpg"), "old");
+        let mut resolver = resolver(Collision::Abort);
+        match resolver.resolve(&a, existing) {
+            Err(CollisionError::Collided { source, .. }) => assert_eq!(source, a),
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    #[test]
+    fn dedupe_skips_identical_files_and_numbers_the_others() {
+        let dir = test_dir("dedupe");
+        let same = write(&dir.join("a.jpg"), "same");
+        let other = write(&dir.join("b.jpg"), "other");
+        let existing = write(&dir.join("t.jpg"), "same");
+        let mut resolver = resolver(Collision::Dedupe);
+        assert_eq!(
+            resolver.resolve(&same, existing.clone()).unwrap(),
+            Resolution::Duplicate(existing.clone())
+        );
+        assert_eq!(
+            resolver.resolve(&other, existing).unwrap(),
+            Resolution::Target(dir.join("t_1.jpg"))
+        );
+    }
+
+    #[test]
+    fn a_source_at_its_target_is_in_place() {
+        let dir = test_dir("in-place");
+        let a = write(&dir.join("t.jpg"), "a");
+        for &strategy in [Collision::Overwrite, Collision::Serial, Collision::Abort].iter() {
+            assert_eq!(
+                resolver(strategy).resolve(&a, dir.join("./t.jpg")).unwrap(),
+                Resolution::InPlace
+            );
+        }
+    }
+}
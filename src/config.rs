@@ -0,0 +1,213 @@
+extern crate toml;
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::value::{Table, Value};
+
+const CONFIG_NAME: &str = "config.toml";
+const PROJECT_CONFIG_NAME: &str = ".rename-by-exif.toml";
+
+/// How a command line option takes its value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OptionKind {
+    Flag,
+    Value,
+    Values,
+    /// A value which is a file path, relative to the config file which sets it.
+    Path,
+}
+
+/// A setting from a config file.
+#[derive(Debug, Clone)]
+pub struct Setting {
+    /// The name of the argument.
+    pub key: &'static str,
+    /// The long option name, which is also the key in the files.
+    pub long: &'static str,
+    pub kind: OptionKind,
+    pub values: Vec<String>,
+    /// The file, and the profile if any, which the setting comes from.
+    pub origin: String,
+}
+
+/// The settings of the config files, the later ones overriding the earlier ones.
+///
+/// The files are the system file, the user file and the project file, which
+/// is the nearest `.rename-by-exif.toml` from the current directory. The
+/// `[profile.NAME]` tables of the selected profile are applied on top of the
+/// top level settings of all files.
+#[derive(Debug, Default)]
+pub struct Settings {
+    settings: Vec<Setting>,
+}
+
+impl Settings {
+    pub fn load(
+        options: &[(&'static str, &'static str, OptionKind)],
+        profile: Option<&str>,
+    ) -> Result<Self, String> {
+        let mut layers = Vec::new();
+        for path in config_paths() {
+            if path.is_file() {
+                layers.push(read_config(&path)?);
+            }
+        }
+        let mut settings = Settings::default();
+        for (path, table) in layers.iter() {
+            settings.merge(options, path, table, "")?;
+            // Check the other profiles too, so that a typo is found early.
+            let profiles = table.get("profile").and_then(Value::as_table);
+            for (name, section) in profiles.into_iter().flatten() {
+                if let Some(section) = section.as_table() {
+                    let prefix = format!("profile.{}.", name);
+                    Settings::default().merge(options, path, section, &prefix)?;
+                }
+            }
+        }
+        if let Some(profile) = profile {
+            let mut found = false;
+            for (path, table) in layers.iter() {
+                let section = table
+                    .get("profile")
+                    .and_then(Value::as_table)
+                    .and_then(|profiles| profiles.get(profile));
+                if let Some(section) = section {
+                    let section = section.as_table().ok_or_else(|| {
+                        format!("{}: profile.{} is not a table", path.display(), profile)
+                    })?;
+                    settings.merge(options, path, section, &format!("profile.{}.", profile))?;
+                    found = true;
+                }
+            }
+            if !found {
+                return Err(format!("Unknown profile: {}", profile));
+            }
+        }
+        Ok(settings)
+    }
+
+    fn merge(
+        &mut self,
+        options: &[(&'static str, &'static str, OptionKind)],
+        path: &Path,
+        table: &Table,
+        prefix: &str,
+    ) -> Result<(), String> {
+        for (key, value) in table.iter() {
+            let invalid =
+                |reason: &str| format!("{}: {}{}: {}", path.display(), prefix, key, reason);
+            if prefix.is_empty() && key == "profile" {
+                if !value.is_table() {
+                    return Err(invalid("expected tables of profiles"));
+                }
+                continue;
+            }
+            let &(key, long, kind) = options
+                .iter()
+                .find(|(_, long, _)| long == key)
+                .ok_or_else(|| invalid("unknown setting"))?;
+            let values = match (kind, value) {
+                (OptionKind::Flag, Value::Boolean(b)) => {
+                    if *b {
+                        Vec::new()
+                    } else {
+                        // A false flag is the same as leaving it out.
+                        self.settings.retain(|s| s.key != key);
+                        continue;
+                    }
+                }
+                (OptionKind::Flag, _) => return Err(invalid("expected a boolean")),
+                (OptionKind::Values, Value::Array(values)) => values
+                    .iter()
+                    .map(scalar_to_string)
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| invalid("expected an array of strings"))?,
+                (OptionKind::Path, Value::String(value)) => {
+                    // An absolute value replaces the directory.
+                    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+                    vec![dir.join(value).to_string_lossy().into_owned()]
+                }
+                (_, value) => {
+                    vec![scalar_to_string(value).ok_or_else(|| invalid("expected a string"))?]
+                }
+            };
+            self.settings.retain(|s| s.key != key);
+            self.settings.push(Setting {
+                key,
+                long,
+                kind,
+                values,
+                origin: match prefix.strip_suffix('.') {
+                    Some(profile) => format!("{} [{}]", path.display(), profile),
+                    None => path.display().to_string(),
+                },
+            });
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Setting> {
+        self.settings.iter().find(|s| s.key == key)
+    }
+
+    /// Returns the settings as command line arguments, except the ones for which
+    /// `given` is true.
+    pub fn to_args<F>(&self, given: F) -> Vec<OsString>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut args = Vec::new();
+        for setting in self.settings.iter().filter(|s| !given(s.key)) {
+            if setting.kind == OptionKind::Flag {
+                args.push(OsString::from(format!("--{}", setting.long)));
+            }
+            for value in setting.values.iter() {
+                // The `=` form keeps values which start with a hyphen.
+                args.push(OsString::from(format!("--{}={}", setting.long, value)));
+            }
+        }
+        args
+    }
+}
+
+fn scalar_to_string(value: &Value) -> Option<String> {
+    match *value {
+        Value::String(ref s) => Some(s.clone()),
+        Value::Integer(i) => Some(i.to_string()),
+        _ => None,
+    }
+}
+
+fn read_config(path: &Path) -> Result<(PathBuf, Table), String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let table = text
+        .parse::<Value>()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    match table {
+        Value::Table(table) => Ok((path.to_path_buf(), table)),
+        _ => Err(format!("{}: not a table", path.display())),
+    }
+}
+
+/// Returns the system, the user and the project config paths in this order.
+pub fn config_paths() -> Vec<PathBuf> {
+    let mut paths = vec![Path::new("/etc/rename-by-exif").join(CONFIG_NAME)];
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    if let Some(config_home) = config_home {
+        paths.push(config_home.join("rename-by-exif").join(CONFIG_NAME));
+    }
+    if let Ok(cwd) = env::current_dir() {
+        if let Some(dir) = cwd
+            .ancestors()
+            .find(|dir| dir.join(PROJECT_CONFIG_NAME).is_file())
+        {
+            paths.push(dir.join(PROJECT_CONFIG_NAME));
+        }
+    }
+    paths
+}
@@ -0,0 +1,371 @@
+extern crate byteorder;
+extern crate exif;
+
+use super::camera::CameraId;
+use super::error::Error;
+use super::exif::{parse_tiff_exif, read_metadata_with_exif, with_camera_id};
+use super::format::MetadataExtractor;
+use super::isobmff::{brands, find_box, parse_boxes, read_top_level_box, BoxError};
+use super::metadata::{DateOptions, Metadata};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use exif::Exif;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::Path;
+
+/// The `moov` box holds the metadata and a small thumbnail.
+const MAX_MOOV_LEN: u64 = 16 * 1024 * 1024;
+
+/// The type of the `uuid` box which holds the Canon metadata boxes.
+const CANON_UUID: [u8; 16] = [
+    0x85, 0xc0, 0xb6, 0x87, 0x82, 0x0f, 0x11, 0xe0, 0x81, 0x11, 0xf4, 0xce, 0x46, 0x2b, 0x6a, 0x48,
+];
+
+const EXIF_IFD_POINTER: u16 = 0x8769;
+const GPS_IFD_POINTER: u16 = 0x8825;
+const INTEROP_IFD_POINTER: u16 = 0xa005;
+const LONG: u16 = 4;
+
+/// Canon CR3 RAW images.
+///
+/// The IFDs are stored as separate TIFF blobs: `CMT1` has IFD0, `CMT2` the
+/// Exif IFD and `CMT4` the GPS IFD.
+pub struct Cr3Extractor;
+
+impl MetadataExtractor for Cr3Extractor {
+    fn name(&self) -> &'static str {
+        "CR3"
+    }
+
+    fn matches_magic(&self, head: &[u8]) -> bool {
+        brands(head).first() == Some(b"crx ")
+    }
+
+    fn matches_extension(&self, lcext: &str) -> bool {
+        lcext == "cr3"
+    }
+
+    fn extract(&self, path: &Path, options: &DateOptions) -> Result<Metadata, Error> {
+        read_cr3_metadata(path, options)
+    }
+
+    fn read_exif(&self, path: &Path) -> Result<Option<(CameraId, Exif)>, Error> {
+        Ok(read_cr3_exif(path)?.map(with_camera_id))
+    }
+}
+
+pub fn read_cr3_metadata(path: &Path, options: &DateOptions) -> Result<Metadata, Error> {
+    let exif = read_cr3_exif(path)?;
+    read_metadata_with_exif(exif.as_ref(), path, options)
+}
+
+fn read_cr3_exif(path: &Path) -> Result<Option<Exif>, Error> {
+    let file = File::open(path)?;
+    match read_cr3_tiff(&mut BufReader::new(file))? {
+        Some(tiff) => Ok(Some(parse_tiff_exif(tiff)?)),
+        None => Ok(None),
+    }
+}
+
+/// Reads the `CMT` boxes and joins them into a single TIFF.
+fn read_cr3_tiff<R: Read + Seek>(reader: &mut R) -> Result<Option<Vec<u8>>, BoxError> {
+    let moov = read_top_level_box(reader, b"moov", MAX_MOOV_LEN)?
+        .ok_or(BoxError::InvalidData("No moov box"))?;
+    let canon = parse_boxes(&moov)?
+        .into_iter()
+        .find(|b| &b.box_type == b"uuid" && b.data.get(..16) == Some(&CANON_UUID[..]));
+    let boxes = match canon {
+        Some(canon) => parse_boxes(&canon.data[16..])?,
+        None => return Ok(None),
+    };
+    let cmt1 = match find_box(&boxes, b"CMT1") {
+        Some(cmt1) => cmt1.data,
+        None => return Ok(None),
+    };
+    let cmt2 = find_box(&boxes, b"CMT2").map(|b| b.data);
+    let cmt4 = find_box(&boxes, b"CMT4").map(|b| b.data);
+    join_tiffs(cmt1, cmt2, cmt4).map(Some)
+}
+
+/// Builds a TIFF whose IFD0 points to the Exif and the GPS IFDs.
+///
+/// Each blob is copied as is, followed by a copy of its IFD with the offsets
+/// moved to the new position of the blob.
+fn join_tiffs(ifd0: &[u8], exif: Option<&[u8]>, gps: Option<&[u8]>) -> Result<Vec<u8>, BoxError> {
+    let order = byte_order(ifd0)?;
+    let mut out = ifd0
+        .get(..8)
+        .ok_or(BoxError::InvalidData("Truncated TIFF header"))?
+        .to_vec();
+    let mut pointers = Vec::new();
+    for &(tag, blob) in [(EXIF_IFD_POINTER, exif), (GPS_IFD_POINTER, gps)].iter() {
+        if let Some(blob) = blob {
+            if byte_order(blob)? != order {
+                return Err(BoxError::InvalidData("Mixed byte orders"));
+            }
+            pointers.push((tag, append_ifd(&mut out, blob, order, &[])?));
+        }
+    }
+    let offset = append_ifd(&mut out, ifd0, order, &pointers)?;
+    order.write_u32(&mut out[4..8], offset);
+    Ok(out)
+}
+
+/// Appends a blob and a relocated copy of its first IFD with the extra `LONG`
+/// entries, and returns the offset of the copy.
+///
+/// The pointers to other IFDs are left out, since those IFDs are not copied.
+fn append_ifd(
+    out: &mut Vec<u8>,
+    blob: &[u8],
+    order: Order,
+    extra: &[(u16, u32)],
+) -> Result<u32, BoxError> {
+    let invalid = || BoxError::InvalidData("Invalid TIFF IFD");
+    align(out);
+    let base = out.len() as u32;
+    out.extend_from_slice(blob);
+    let start = order.read_u32(blob.get(4..8).ok_or_else(invalid)?) as usize;
+    let entries_start = start.checked_add(2).ok_or_else(invalid)?;
+    let count = usize::from(order.read_u16(blob.get(start..entries_start).ok_or_else(invalid)?));
+    let entries = entries_start
+        .checked_add(count * 12)
+        .and_then(|end| blob.get(entries_start..end))
+        .ok_or_else(invalid)?;
+    let mut copied = Vec::new();
+    for entry in entries.chunks_exact(12) {
+        let tag = order.read_u16(&entry[0..2]);
+        let value_type = order.read_u16(&entry[2..4]);
+        let count = order.read_u32(&entry[4..8]);
+        let size = match type_size(value_type) {
+            Some(size) => u64::from(size) * u64::from(count),
+            None => continue,
+        };
+        if [EXIF_IFD_POINTER, GPS_IFD_POINTER, INTEROP_IFD_POINTER].contains(&tag) {
+            continue;
+        }
+        let mut entry = entry.to_vec();
+        if size > 4 {
+            let offset = order.read_u32(&entry[8..12]);
+            let offset = offset.checked_add(base).ok_or_else(invalid)?;
+            order.write_u32(&mut entry[8..12], offset);
+        }
+        copied.push((tag, entry));
+    }
+    for &(tag, value) in extra.iter() {
+        let mut entry = vec![0; 12];
+        order.write_u16(&mut entry[0..2], tag);
+        order.write_u16(&mut entry[2..4], LONG);
+        order.write_u32(&mut entry[4..8], 1);
+        order.write_u32(&mut entry[8..12], value);
+        copied.push((tag, entry));
+    }
+    // The entries of an IFD are sorted by tag.
+    copied.sort_by_key(|&(tag, _)| tag);
+    align(out);
+    let offset = out.len() as u32;
+    let mut count = [0; 2];
+    order.write_u16(&mut count, copied.len() as u16);
+    out.extend_from_slice(&count);
+    for (_, entry) in copied.iter() {
+        out.extend_from_slice(entry);
+    }
+    // There is no next IFD.
+    out.extend_from_slice(&[0; 4]);
+    Ok(offset)
+}
+
+/// Pads to a word boundary, where TIFF offsets should be.
+fn align(out: &mut Vec<u8>) {
+    if out.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+/// The size of a value of a TIFF field type.
+fn type_size(value_type: u16) -> Option<u32> {
+    match value_type {
+        1 | 2 | 6 | 7 => Some(1),
+        3 | 8 => Some(2),
+        4 | 9 | 11 | 13 => Some(4),
+        5 | 10 | 12 => Some(8),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Order {
+    Little,
+    Big,
+}
+
+impl Order {
+    fn read_u16(self, buf: &[u8]) -> u16 {
+        match self {
+            Order::Little => LittleEndian::read_u16(buf),
+            Order::Big => BigEndian::read_u16(buf),
+        }
+    }
+
+    fn read_u32(self, buf: &[u8]) -> u32 {
+        match self {
+            Order::Little => LittleEndian::read_u32(buf),
+            Order::Big => BigEndian::read_u32(buf),
+        }
+    }
+
+    fn write_u16(self, buf: &mut [u8], n: u16) {
+        match self {
+            Order::Little => LittleEndian::write_u16(buf, n),
+            Order::Big => BigEndian::write_u16(buf, n),
+        }
+    }
+
+    fn write_u32(self, buf: &mut [u8], n: u32) {
+        match self {
+            Order::Little => LittleEndian::write_u32(buf, n),
+            Order::Big => BigEndian::write_u32(buf, n),
+        }
+    }
+}
+
+fn byte_order(tiff: &[u8]) -> Result<Order, BoxError> {
+    match tiff.get(..4) {
+        Some(b"II*\0") => Ok(Order::Little),
+        Some(b"MM\0*") => Ok(Order::Big),
+        _ => Err(BoxError::InvalidData("Invalid TIFF header")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate exif;
+
+    use super::*;
+    use exif::{Exif, In, Tag, Value};
+
+    /// Builds a TIFF with one IFD of ASCII fields, whose longer values follow the IFD.
+    fn tiff(order: Order, fields: &[(u16, &str)]) -> Vec<u8> {
+        let mut out = match order {
+            Order::Little => b"II*\0\0\0\0\0".to_vec(),
+            Order::Big => b"MM\0*\0\0\0\0".to_vec(),
+        };
+        order.write_u32(&mut out[4..8], 8);
+        let data_start = 8 + 2 + fields.len() * 12 + 4;
+        let mut ifd = vec![0; 2];
+        order.write_u16(&mut ifd, fields.len() as u16);
+        let mut data = Vec::new();
+        for &(tag, value) in fields.iter() {
+            let value = format!("{}\0", value).into_bytes();
+            let mut entry = vec![0; 12];
+            order.write_u16(&mut entry[0..2], tag);
+            order.write_u16(&mut entry[2..4], 2);
+            order.write_u32(&mut entry[4..8], value.len() as u32);
+            if value.len() <= 4 {
+                entry[8..8 + value.len()].copy_from_slice(&value);
+            } else {
+                order.write_u32(&mut entry[8..12], (data_start + data.len()) as u32);
+                data.extend_from_slice(&value);
+            }
+            ifd.extend_from_slice(&entry);
+        }
+        ifd.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&ifd);
+        out.extend_from_slice(&data);
+        out
+    }
+
+    fn ascii(exif: &Exif, tag: Tag) -> Option<String> {
+        match exif.get_field(tag, In::PRIMARY)?.value {
+            Value::Ascii(ref values) => Some(String::from_utf8_lossy(&values[0]).to_string()),
+            _ => None,
+        }
+    }
+
+    /// Returns the offsets of the out of line values of the IFD at `start`.
+    fn value_offsets(tiff: &[u8], order: Order, start: usize) -> Vec<(u16, usize, usize)> {
+        let count = usize::from(order.read_u16(&tiff[start..]));
+        (0..count)
+            .map(|i| &tiff[start + 2 + i * 12..])
+            .filter(|entry| order.read_u32(&entry[4..8]) > 4)
+            .map(|entry| {
+                (
+                    order.read_u16(&entry[0..2]),
+                    order.read_u32(&entry[8..12]) as usize,
+                    order.read_u32(&entry[4..8]) as usize,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn relocated_offsets_point_into_each_blob() {
+        let order = Order::Little;
+        let cmt1 = tiff(order, &[(0x010f, "Canon"), (0x0110, "Canon EOS R5")]);
+        let cmt2 = tiff(order, &[(0x9003, "2019:10:01 12:34:56")]);
+        let cmt4 = tiff(order, &[(0x0001, "N"), (0x001d, "2019:10:01")]);
+        let joined = join_tiffs(&cmt1, Some(&cmt2), Some(&cmt4)).unwrap();
+
+        let ifd0 = order.read_u32(&joined[4..8]) as usize;
+        let offsets = value_offsets(&joined, order, ifd0);
+        assert_eq!(offsets.len(), 2);
+        for (tag, offset, len) in offsets {
+            let value = &joined[offset..offset + len];
+            let expected: &[u8] = if tag == 0x010f {
+                b"Canon\0"
+            } else {
+                b"Canon EOS R5\0"
+            };
+            assert_eq!(value, expected);
+            // The value is in the copy of CMT1, which is after the CMT2 and the CMT4 blobs.
+            assert!(offset > cmt2.len() + cmt4.len());
+        }
+    }
+
+    #[test]
+    fn date_time_original_survives_the_join() {
+        for &order in [Order::Little, Order::Big].iter() {
+            let cmt1 = tiff(order, &[(0x010f, "Canon")]);
+            let cmt2 = tiff(
+                order,
+                &[(0x9003, "2019:10:01 12:34:56"), (0x9011, "+09:00")],
+            );
+            let cmt4 = tiff(order, &[(0x001d, "2019:10:01")]);
+            let joined = join_tiffs(&cmt1, Some(&cmt2), Some(&cmt4)).unwrap();
+            let exif = parse_tiff_exif(joined).unwrap();
+            assert_eq!(ascii(&exif, Tag::Make).as_deref(), Some("Canon"));
+            assert_eq!(
+                ascii(&exif, Tag::DateTimeOriginal).as_deref(),
+                Some("2019:10:01 12:34:56")
+            );
+            assert_eq!(
+                ascii(&exif, Tag::OffsetTimeOriginal).as_deref(),
+                Some("+09:00")
+            );
+            assert_eq!(
+                ascii(&exif, Tag::GPSDateStamp).as_deref(),
+                Some("2019:10:01")
+            );
+        }
+    }
+
+    #[test]
+    fn missing_cmt2_and_cmt4_are_left_out() {
+        let cmt1 = tiff(Order::Little, &[(0x0110, "Canon EOS R5")]);
+        let exif = parse_tiff_exif(join_tiffs(&cmt1, None, None).unwrap()).unwrap();
+        assert_eq!(ascii(&exif, Tag::Model).as_deref(), Some("Canon EOS R5"));
+        assert_eq!(ascii(&exif, Tag::DateTimeOriginal), None);
+    }
+
+    #[test]
+    fn broken_blobs_are_rejected() {
+        let cmt1 = tiff(Order::Little, &[(0x010f, "Canon")]);
+        let big = tiff(Order::Big, &[(0x9003, "2019:10:01 12:34:56")]);
+        assert!(join_tiffs(&cmt1, Some(&big), None).is_err());
+        assert!(join_tiffs(&cmt1[..12], None, None).is_err());
+        assert!(join_tiffs(b"JUNK\0\0\0\x08", None, None).is_err());
+        let mut beyond = cmt1.clone();
+        Order::Little.write_u32(&mut beyond[4..8], 0xffff_fff0);
+        assert!(join_tiffs(&beyond, None, None).is_err());
+    }
+}
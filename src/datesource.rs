@@ -0,0 +1,400 @@
+extern crate chrono;
+extern crate chrono_tz;
+
+use super::exif::{DateTimeError, DateTimeErrorKind};
+use super::localtime::{resolve_local_time_in, ResolvedTime};
+use super::metadata::DateOptions;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::UTC;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+pub const DEFAULT_DATE_SOURCES: &str = "original,digitized,datetime,gps,xmp";
+
+const XMP_DATE_CREATED: &str = "photoshop:DateCreated";
+
+/// The XMP packet of JPEG, TIFF and most RAW formats is near the start,
+/// so only this much of a media file is scanned, unlike a sidecar.
+const MAX_EMBEDDED_XMP_SCAN: u64 = 4 * 1024 * 1024;
+
+/// A place to take the capture date of a file from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DateSource {
+    /// Exif `DateTimeOriginal`, or the creation date of a video.
+    Original,
+    /// Exif `DateTimeDigitized`, a.k.a. `CreateDate`.
+    Digitized,
+    /// Exif `DateTime` (0x0132), which editors often update.
+    DateTime,
+    /// Exif `GPSDateStamp` and `GPSTimeStamp` in UTC.
+    Gps,
+    /// XMP `photoshop:DateCreated` in a sidecar or in the file.
+    Xmp,
+    /// A date in the file name, e.g. `IMG_20191001_123456.jpg`.
+    Filename,
+    /// The modification time of the file.
+    Mtime,
+}
+
+impl DateSource {
+    /// Returns true if the source is read from the Exif.
+    pub fn is_exif(self) -> bool {
+        match self {
+            DateSource::Original
+            | DateSource::Digitized
+            | DateSource::DateTime
+            | DateSource::Gps => true,
+            DateSource::Xmp | DateSource::Filename | DateSource::Mtime => false,
+        }
+    }
+}
+
+impl FromStr for DateSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "original" => Ok(DateSource::Original),
+            "digitized" => Ok(DateSource::Digitized),
+            "datetime" => Ok(DateSource::DateTime),
+            "gps" => Ok(DateSource::Gps),
+            "xmp" => Ok(DateSource::Xmp),
+            "filename" => Ok(DateSource::Filename),
+            "mtime" => Ok(DateSource::Mtime),
+            _ => Err(format!("Unknown date source: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for DateSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            DateSource::Original => "original",
+            DateSource::Digitized => "digitized",
+            DateSource::DateTime => "datetime",
+            DateSource::Gps => "gps",
+            DateSource::Xmp => "xmp",
+            DateSource::Filename => "filename",
+            DateSource::Mtime => "mtime",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Parses a comma separated list of date sources, e.g. `original,filename,mtime`.
+pub fn parse_date_sources(s: &str) -> Result<Vec<DateSource>, String> {
+    let mut sources = Vec::new();
+    for name in s.split(',').map(str::trim) {
+        let source = name.parse()?;
+        if sources.contains(&source) {
+            return Err(format!("Duplicate date source: {}", name));
+        }
+        sources.push(source);
+    }
+    Ok(sources)
+}
+
+/// Tries the date sources in order and returns the first date found with its source.
+///
+/// `read_exif` reads the Exif sources of the format. A blank or malformed date
+/// counts as missing, and is only reported if no other source has a date.
+pub(crate) fn resolve_date<F>(
+    path: &Path,
+    options: &DateOptions,
+    mut read_exif: F,
+) -> Result<Option<(ResolvedTime, DateSource)>, DateTimeError>
+where
+    F: FnMut(DateSource) -> Result<Option<ResolvedTime>, DateTimeError>,
+{
+    let mut unusable = None;
+    for &source in options.sources.iter() {
+        let result = match source {
+            _ if source.is_exif() => read_exif(source),
+            DateSource::Xmp => read_xmp_date(path, options),
+            DateSource::Filename => read_filename_date(path, options),
+            _ => Ok(read_mtime(path)),
+        };
+        match result {
+            Ok(Some(dt)) => return Ok(Some((dt, source))),
+            Ok(None) => {}
+            Err(e)
+                if e.kind == DateTimeErrorKind::Blank || e.kind == DateTimeErrorKind::Malformed =>
+            {
+                unusable.get_or_insert(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    match unusable {
+        Some(e) => Err(e),
+        None => Ok(None),
+    }
+}
+
+fn read_xmp_date(
+    path: &Path,
+    options: &DateOptions,
+) -> Result<Option<ResolvedTime>, DateTimeError> {
+    // A sidecar is where editors put their changes, so prefer it.
+    let candidates = [
+        (path.with_extension("xmp"), u64::MAX),
+        (path.with_extension("XMP"), u64::MAX),
+        (path.to_path_buf(), MAX_EMBEDDED_XMP_SCAN),
+    ];
+    for (candidate, limit) in candidates.iter() {
+        let data = match read_head(candidate, *limit) {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        if let Some(value) = find_xmp_property(&data, XMP_DATE_CREATED) {
+            return parse_xmp_date(&value, options).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+fn read_head(path: &Path, limit: u64) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    File::open(path)?.take(limit).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Finds a property either as an attribute or as an element.
+fn find_xmp_property(data: &[u8], name: &str) -> Option<String> {
+    let name = name.as_bytes();
+    let mut rest = data;
+    while let Some(pos) = rest.windows(name.len()).position(|w| w == name) {
+        rest = &rest[pos + name.len()..];
+        let trimmed = {
+            let start = rest.iter().position(|b| !b.is_ascii_whitespace())?;
+            &rest[start..]
+        };
+        let value = match trimmed.first() {
+            Some(b'=') => {
+                let quoted = &trimmed[1..];
+                let start = quoted.iter().position(|b| !b.is_ascii_whitespace())?;
+                let quote = quoted[start];
+                let value = &quoted[start + 1..];
+                value
+                    .iter()
+                    .position(|b| *b == quote)
+                    .map(|end| &value[..end])
+            }
+            Some(b'>') => {
+                let value = &trimmed[1..];
+                value
+                    .iter()
+                    .position(|b| *b == b'<')
+                    .map(|end| &value[..end])
+            }
+            _ => None,
+        };
+        if let Some(value) = value {
+            return Some(String::from_utf8_lossy(value).trim().to_string());
+        }
+    }
+    None
+}
+
+/// Parses an XMP date, e.g. `2019-10-01T12:34:56+02:00`, `2019-10-01T12:34` or `2019-10-01`.
+fn parse_xmp_date(value: &str, options: &DateOptions) -> Result<ResolvedTime, DateTimeError> {
+    let malformed = || DateTimeError::new(XMP_DATE_CREATED, value, DateTimeErrorKind::Malformed);
+    let with_offset = match value.strip_suffix('Z') {
+        Some(v) => format!("{}+00:00", v),
+        None => value.to_string(),
+    };
+    for format in ["%Y-%m-%dT%H:%M:%S%.f%:z", "%Y-%m-%dT%H:%M%:z"].iter() {
+        if let Ok(dt) = DateTime::parse_from_str(&with_offset, format) {
+            return Ok(ResolvedTime::from_local(&dt, None));
+        }
+    }
+    let naive = ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()
+                .and_then(|d| d.and_hms_opt(0, 0, 0))
+        })
+        .ok_or_else(malformed)?;
+    resolve_local_time_in(options.from_tz, &naive, options.dst_policy)
+        .map_err(|e| DateTimeError::from_local_time_error(XMP_DATE_CREATED, value, e))
+}
+
+fn read_filename_date(
+    path: &Path,
+    options: &DateOptions,
+) -> Result<Option<ResolvedTime>, DateTimeError> {
+    let stem = match path.file_stem() {
+        Some(stem) => stem.to_string_lossy(),
+        None => return Ok(None),
+    };
+    match date_in_filename(&stem) {
+        Some(naive) => resolve_local_time_in(options.from_tz, &naive, options.dst_policy)
+            .map(Some)
+            .map_err(|e| DateTimeError::from_local_time_error("file name", &stem, e)),
+        None => Ok(None),
+    }
+}
+
+/// Finds the first date time like `20191001_123456` or `2019-10-01 12.34.56`,
+/// or a date like `20191001`, in a file name.
+fn date_in_filename(stem: &str) -> Option<NaiveDateTime> {
+    let chars: Vec<char> = stem.chars().collect();
+    for start in 0..chars.len() {
+        if !chars[start].is_ascii_digit() || (start > 0 && chars[start - 1].is_ascii_digit()) {
+            continue;
+        }
+        let digits = collect_date_digits(&chars[start..]);
+        if !(digits.starts_with("19") || digits.starts_with("20")) {
+            continue;
+        }
+        if digits.len() >= 14 {
+            if let Ok(dt) = NaiveDateTime::parse_from_str(&digits[..14], "%Y%m%d%H%M%S") {
+                return Some(dt);
+            }
+        }
+        if digits.len() >= 8 {
+            if let Ok(d) = NaiveDate::parse_from_str(&digits[..8], "%Y%m%d") {
+                return d.and_hms_opt(0, 0, 0);
+            }
+        }
+    }
+    None
+}
+
+/// Collects up to 14 digits, allowing a single separator between them.
+fn collect_date_digits(chars: &[char]) -> String {
+    let mut digits = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            if digits.len() == 14 {
+                break;
+            }
+        } else if "-_ .:T".contains(c) && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit()) {
+            continue;
+        } else {
+            break;
+        }
+    }
+    digits
+}
+
+fn read_mtime(path: &Path) -> Option<ResolvedTime> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    Some(ResolvedTime::from_utc(
+        DateTime::<Utc>::from(modified).with_timezone(&UTC),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Tz;
+
+    fn naive(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn options(sources: &str) -> DateOptions {
+        DateOptions {
+            from_tz: Some(Tz::UTC),
+            sources: parse_date_sources(sources).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn date_in_filename_reads_date_times_with_separators() {
+        let expected = Some(naive("2019-10-01 12:34:56"));
+        assert_eq!(date_in_filename("IMG_20191001_123456"), expected);
+        assert_eq!(date_in_filename("20191001123456"), expected);
+        assert_eq!(date_in_filename("Screenshot 2019-10-01 12.34.56"), expected);
+        assert_eq!(date_in_filename("PXL_20191001T123456789"), expected);
+        assert_eq!(date_in_filename("VID-2019-10-01-12-34-56"), expected);
+    }
+
+    #[test]
+    fn date_in_filename_falls_back_to_the_date() {
+        let expected = Some(naive("2019-10-01 00:00:00"));
+        assert_eq!(date_in_filename("IMG-20191001-WA0001"), expected);
+        // An invalid time does not discard a valid date.
+        assert_eq!(date_in_filename("20191001_996699"), expected);
+    }
+
+    #[test]
+    fn date_in_filename_ignores_other_numbers() {
+        assert_eq!(date_in_filename("IMG_0001"), None);
+        assert_eq!(date_in_filename("DSC12345678"), None);
+        assert_eq!(date_in_filename("20191301"), None);
+        // The digits must start at a number boundary.
+        assert_eq!(date_in_filename("120191001"), None);
+        assert_eq!(
+            date_in_filename("trip 3 20191001"),
+            Some(naive("2019-10-01 00:00:00"))
+        );
+    }
+
+    #[test]
+    fn xmp_property_is_found_as_an_attribute_or_an_element() {
+        let attribute = br#"<rdf:Description photoshop:DateCreated = '2019-10-01T12:34:56'/>"#;
+        let element = b"<photoshop:DateCreated>2019-10-01</photoshop:DateCreated>";
+        assert_eq!(
+            find_xmp_property(attribute, XMP_DATE_CREATED).as_deref(),
+            Some("2019-10-01T12:34:56")
+        );
+        assert_eq!(
+            find_xmp_property(element, XMP_DATE_CREATED).as_deref(),
+            Some("2019-10-01")
+        );
+        assert_eq!(find_xmp_property(b"<x/>", XMP_DATE_CREATED), None);
+    }
+
+    #[test]
+    fn malformed_and_blank_dates_fall_through_to_the_next_source() {
+        let path = Path::new("/nonexistent/IMG_20191001_123456.jpg");
+        let (time, source) = resolve_date(path, &options("original,filename"), |_| {
+            Err(DateTimeError::new(
+                "DateTimeOriginal",
+                "0000:00:00 00:00:00",
+                DateTimeErrorKind::Malformed,
+            ))
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(source, DateSource::Filename);
+        assert_eq!(time.utc.naive_utc(), naive("2019-10-01 12:34:56"));
+    }
+
+    #[test]
+    fn the_first_unusable_date_is_reported_if_no_source_has_one() {
+        let path = Path::new("/nonexistent/IMG_0001.jpg");
+        let error = resolve_date(path, &options("original,digitized,filename"), |source| {
+            let kind = match source {
+                DateSource::Original => DateTimeErrorKind::Blank,
+                _ => DateTimeErrorKind::Malformed,
+            };
+            Err(DateTimeError::new(source, "", kind))
+        })
+        .unwrap_err();
+        assert_eq!(error.kind, DateTimeErrorKind::Blank);
+    }
+
+    #[test]
+    fn other_date_errors_stop_the_chain() {
+        let path = Path::new("/nonexistent/IMG_20191001_123456.jpg");
+        let error = resolve_date(path, &options("original,filename"), |_| {
+            Err(DateTimeError::new(
+                "DateTimeOriginal",
+                "2019:03:31 02:30:00",
+                DateTimeErrorKind::NonexistentLocalTime,
+            ))
+        })
+        .unwrap_err();
+        assert_eq!(error.kind, DateTimeErrorKind::NonexistentLocalTime);
+    }
+}
@@ -0,0 +1,70 @@
+extern crate exif;
+
+use super::exif::DateTimeError;
+use super::isobmff::BoxError;
+use std::fmt;
+use std::io;
+
+/// An error reading the metadata of a file.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// No registered format reads the file.
+    UnknownFormat,
+    /// The file does not have the structure of its format.
+    InvalidData(&'static str),
+    /// The Exif of the file cannot be parsed.
+    Exif(exif::Error),
+    /// A date field of the file cannot be used.
+    DateTime(DateTimeError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => err.fmt(f),
+            Error::UnknownFormat => write!(f, "Unknown file format"),
+            Error::InvalidData(s) => write!(f, "{}", s),
+            Error::Exif(ref err) => err.fmt(f),
+            Error::DateTime(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            Error::Exif(ref err) => Some(err),
+            Error::DateTime(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<exif::Error> for Error {
+    fn from(err: exif::Error) -> Error {
+        Error::Exif(err)
+    }
+}
+
+impl From<DateTimeError> for Error {
+    fn from(err: DateTimeError) -> Error {
+        Error::DateTime(err)
+    }
+}
+
+impl From<BoxError> for Error {
+    fn from(err: BoxError) -> Error {
+        match err {
+            BoxError::Io(err) => Error::Io(err),
+            BoxError::InvalidData(s) => Error::InvalidData(s),
+        }
+    }
+}
@@ -2,64 +2,448 @@ extern crate chrono;
 extern crate chrono_tz;
 extern crate exif;
 
-use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use super::camera::CameraId;
+use super::datesource::{resolve_date, DateSource};
+use super::error::Error;
+use super::format::{FormatRegistry, MetadataExtractor};
+use super::gpstz::timezone_at;
+use super::localtime::{resolve_local_time_in, LocalTimeError, ResolvedTime};
+use super::metadata::{CaptureTime, DateOptions, Metadata};
+use super::skew::ClockError;
+use chrono::{DateTime, Duration, FixedOffset, NaiveDate, NaiveDateTime, TimeZone, Timelike};
 use chrono_tz::{Tz, UTC};
-use exif::{Reader, Tag};
+use exif::{Exif, In, Reader, Tag, Value};
+use std::fmt;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufRead, BufReader, Seek};
+use std::path::{Path, PathBuf};
 
-pub fn read_exif_date_time_original(
-    filename: &str,
-    from_tz: Option<Tz>,
-) -> Result<(Option<DateTime<Tz>>), String> {
-    let file = File::open(filename).map_err(|e| e.to_string())?;
-    let reader = Reader::new(&mut BufReader::new(&file)).map_err(|e| e.to_string())?;
-    return Ok(read_date_time_original_as_utc(&reader, from_tz));
+/// A date time field which cannot be converted to a timestamp.
+#[derive(Debug)]
+pub struct DateTimeError {
+    pub path: Option<PathBuf>,
+    /// The Exif tag or other field which holds the value.
+    pub field: String,
+    pub value: String,
+    pub kind: DateTimeErrorKind,
 }
 
-pub fn read_date_time_original_as_utc(reader: &Reader, from_tz: Option<Tz>) -> Option<DateTime<Tz>> {
-    let date_time_original = reader.get_field(Tag::DateTimeOriginal, false);
-    if let Some(dto) = date_time_original {
-        let offset_time_original = reader.get_field(Tag::OffsetTimeOriginal, false);
-        return Some(match (from_tz, offset_time_original) {
-            // If the `OffsetTimeOriginal` exists, prefer it rather than the `from_tz`.
-            (_, Some(oto)) => utc_date_time_original_with_offset(&dto, &oto),
-            (Some(tz), None) => utc_date_time_original_with_timezone(&dto, &tz),
-            (None, None) => utc_date_time_original(&dto),
-        });
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DateTimeErrorKind {
+    /// The value is empty or filled with blanks, which the Exif spec allows for unknown dates.
+    Blank,
+    /// The value does not match the Exif date time format or is out of range.
+    Malformed,
+    /// The local time does not exist in the time zone, e.g. in a DST gap.
+    NonexistentLocalTime,
+    /// The local time is repeated at the end of DST and the DST policy rejects it.
+    AmbiguousLocalTime,
+}
+
+impl DateTimeError {
+    pub(crate) fn new<F: fmt::Display>(field: F, value: &str, kind: DateTimeErrorKind) -> Self {
+        DateTimeError {
+            path: None,
+            field: field.to_string(),
+            value: String::from(value),
+            kind,
+        }
+    }
+
+    pub(crate) fn from_local_time_error<F: fmt::Display>(
+        field: F,
+        value: &str,
+        e: LocalTimeError,
+    ) -> Self {
+        let kind = match e {
+            LocalTimeError::Ambiguous => DateTimeErrorKind::AmbiguousLocalTime,
+            LocalTimeError::Nonexistent => DateTimeErrorKind::NonexistentLocalTime,
+        };
+        DateTimeError::new(field, value, kind)
+    }
+
+    pub(crate) fn with_path(mut self, path: &Path) -> Self {
+        self.path = Some(path.to_path_buf());
+        self
+    }
+}
+
+impl fmt::Display for DateTimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self.kind {
+            DateTimeErrorKind::Blank => "blank value",
+            DateTimeErrorKind::Malformed => "malformed value",
+            DateTimeErrorKind::NonexistentLocalTime => "nonexistent local time",
+            DateTimeErrorKind::AmbiguousLocalTime => "ambiguous local time",
+        };
+        write!(f, "{} {:?}: {}", self.field, self.value, reason)
+    }
+}
+
+impl std::error::Error for DateTimeError {}
+
+/// The containers which kamadak-exif reads: TIFF and the RAW formats based on
+/// it, JPEG, PNG and WebP.
+pub struct ExifExtractor;
+
+const EXIF_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "jpe", "tif", "tiff", "png", "webp", "dng", "nef", "arw", "cr2", "pef", "srw",
+];
+
+impl MetadataExtractor for ExifExtractor {
+    fn name(&self) -> &'static str {
+        "Exif"
+    }
+
+    fn matches_magic(&self, head: &[u8]) -> bool {
+        head.starts_with(b"II*\0")
+            || head.starts_with(b"MM\0*")
+            || head.starts_with(b"\xff\xd8")
+            || head.starts_with(b"\x89PNG\r\n\x1a\n")
+            || (head.starts_with(b"RIFF") && head.get(8..12) == Some(&b"WEBP"[..]))
+    }
+
+    fn matches_extension(&self, lcext: &str) -> bool {
+        EXIF_EXTENSIONS.contains(&lcext)
+    }
+
+    fn extract(&self, path: &Path, options: &DateOptions) -> Result<Metadata, Error> {
+        read_exif_metadata(path, options)
+    }
+
+    fn read_exif(&self, path: &Path) -> Result<Option<(CameraId, Exif)>, Error> {
+        Ok(read_exif_file(path)?.map(with_camera_id))
+    }
+}
+
+pub fn read_exif_metadata(path: &Path, options: &DateOptions) -> Result<Metadata, Error> {
+    let exif = read_exif_file(path)?;
+    read_metadata_with_exif(exif.as_ref(), path, options)
+}
+
+/// Reads the metadata of a file whose Exif, if any, has been read by the caller.
+pub fn read_metadata_with_exif(
+    exif: Option<&Exif>,
+    path: &Path,
+    options: &DateOptions,
+) -> Result<Metadata, Error> {
+    let body = read_camera_id(exif);
+    read_metadata(exif, &body, path, options, |_| Ok(None)).map_err(|e| e.with_path(path).into())
+}
+
+/// Parses the Exif of a TIFF header and its IFDs, which other containers embed.
+pub fn parse_tiff_exif(tiff: Vec<u8>) -> Result<Exif, Error> {
+    Ok(Reader::new().read_raw(tiff)?)
+}
+
+fn read_exif_file(path: &Path) -> Result<Option<Exif>, Error> {
+    let file = File::open(path)?;
+    read_exif_container(&mut BufReader::new(&file))
+}
+
+/// Reads the Exif of a container like JPEG, which some RAW formats embed.
+pub fn read_exif_container<R: BufRead + Seek>(reader: &mut R) -> Result<Option<Exif>, Error> {
+    match Reader::new().read_from_container(reader) {
+        Ok(exif) => Ok(Some(exif)),
+        // A file without Exif may still be dated by the other date sources.
+        Err(exif::Error::NotFound(_)) => Ok(None),
+        Err(e) => Err(e.into()),
     }
-    return None;
 }
 
-#[inline]
-fn field_as_string(field: &exif::Field) -> String {
-    return field.value.display_as(field.tag).to_string();
+/// Reads the body and the `DateTimeOriginal` of a reference photo as the camera clock showed it.
+pub fn read_camera_clock(
+    formats: &FormatRegistry,
+    path: &Path,
+) -> Result<(CameraId, NaiveDateTime), ClockError> {
+    let no_date = || ClockError::NoDate(path.to_path_buf());
+    let (body, exif) = formats
+        .read_exif(path)
+        .map_err(|e| ClockError::File(path.to_path_buf(), e))?
+        .ok_or_else(no_date)?;
+    let clock = read_clock(&exif, Tag::DateTimeOriginal, Tag::SubSecTimeOriginal)
+        .map_err(|e| ClockError::File(path.to_path_buf(), e.with_path(path).into()))?
+        .map(|(_, naive)| naive)
+        .ok_or_else(no_date)?;
+    Ok((body, clock))
 }
 
-fn date_time_original_as_naive(dto: &exif::Field) -> NaiveDateTime {
-    let dt_str = field_as_string(&dto);
-    dbg!(&dt_str);
-    return NaiveDateTime::parse_from_str(&dt_str, "%Y-%m-%d %H:%M:%S").unwrap();
+/// Returns the GPS time minus the `DateTimeOriginal` of a file which has both.
+pub fn read_gps_clock_skew(
+    formats: &FormatRegistry,
+    path: &Path,
+    options: &DateOptions,
+) -> Result<Option<(CameraId, Duration)>, Error> {
+    let (body, exif) = match formats.read_exif(path)? {
+        Some(exif) => exif,
+        None => return Ok(None),
+    };
+    let read = || -> Result<Option<Duration>, DateTimeError> {
+        let gps = match read_gps_date(&exif)? {
+            Some(gps) => gps,
+            None => return Ok(None),
+        };
+        let dt = read_exif_date(&exif, &body, DateSource::Original, options)?;
+        Ok(dt.map(|dt| gps - dt.utc))
+    };
+    let skew = read().map_err(|e| e.with_path(path))?;
+    Ok(skew.map(|skew| (body, skew)))
 }
 
-fn utc_date_time_original(dto: &exif::Field) -> DateTime<Tz> {
-    return Local
-        .from_local_datetime(&date_time_original_as_naive(dto))
-        .unwrap()
-        .with_timezone(&UTC);
+/// Pairs the Exif with the body in it, for a format which has no other camera fields.
+pub fn with_camera_id(exif: Exif) -> (CameraId, Exif) {
+    (read_camera_id(Some(&exif)), exif)
 }
 
-fn utc_date_time_original_with_timezone(dto: &exif::Field, tz: &Tz) -> DateTime<Tz> {
-    return tz
-        .from_local_datetime(&date_time_original_as_naive(dto))
-        .unwrap()
-        .with_timezone(&UTC);
+pub fn read_camera_id(exif: Option<&Exif>) -> CameraId {
+    let field = |tag| exif.and_then(|exif| read_ascii_field(exif, tag));
+    CameraId {
+        make: field(Tag::Make),
+        model: field(Tag::Model),
+        serial: field(Tag::BodySerialNumber),
+    }
+}
+
+/// Reads the metadata, taking the date from the first date source which has one.
+///
+/// `body` is the camera which took the file, and `fallback` gives a format
+/// specific date for an Exif date source which the Exif lacks.
+pub fn read_metadata<F>(
+    exif: Option<&Exif>,
+    body: &CameraId,
+    path: &Path,
+    options: &DateOptions,
+    fallback: F,
+) -> Result<Metadata, DateTimeError>
+where
+    F: Fn(DateSource) -> Result<Option<ResolvedTime>, DateTimeError>,
+{
+    let date = resolve_date(path, options, |source| {
+        let dt = match exif {
+            Some(exif) => read_exif_date(exif, body, source, options)?,
+            None => None,
+        };
+        match dt {
+            Some(dt) => Ok(Some(dt)),
+            None => fallback(source),
+        }
+    })?;
+    let capture_time = date.map(|(dt, source)| CaptureTime::new(dt, source));
+    let field = |tag| exif.and_then(|exif| read_ascii_field(exif, tag));
+    Ok(Metadata {
+        capture_time,
+        make: body.make.clone(),
+        model: body.model.clone(),
+        lens: field(Tag::LensModel),
+        subsec: capture_time
+            .and_then(|t| date_tags(t.source))
+            .and_then(|(_, subsec_tag, _)| field(subsec_tag))
+            .filter(|s| is_subsec(s)),
+    })
+}
+
+/// Returns the date time, sub-second and offset tags of an Exif date source.
+fn date_tags(source: DateSource) -> Option<(Tag, Tag, Tag)> {
+    match source {
+        DateSource::Original => Some((
+            Tag::DateTimeOriginal,
+            Tag::SubSecTimeOriginal,
+            Tag::OffsetTimeOriginal,
+        )),
+        DateSource::Digitized => Some((
+            Tag::DateTimeDigitized,
+            Tag::SubSecTimeDigitized,
+            Tag::OffsetTimeDigitized,
+        )),
+        DateSource::DateTime => Some((Tag::DateTime, Tag::SubSecTime, Tag::OffsetTime)),
+        _ => None,
+    }
+}
+
+/// Returns the date of an Exif date source in UTC, with the offset of the clock
+/// and what the DST policy did to it if any.
+///
+/// The sub-second tag is added as the fraction of the second, and the clock
+/// correction and the camera profile of the body are applied to the camera dates.
+pub fn read_exif_date(
+    exif: &Exif,
+    body: &CameraId,
+    source: DateSource,
+    options: &DateOptions,
+) -> Result<Option<ResolvedTime>, DateTimeError> {
+    if source == DateSource::Gps {
+        return Ok(read_gps_date(exif)?.map(ResolvedTime::from_utc));
+    }
+    let (tag, subsec_tag, offset_tag) = match date_tags(source) {
+        Some(tags) => tags,
+        None => return Ok(None),
+    };
+    let (value, mut naive) = match read_clock(exif, tag, subsec_tag)? {
+        Some(clock) => clock,
+        None => return Ok(None),
+    };
+    let profile = options.cameras.find(body);
+    if let Some(offset) = options
+        .clock
+        .offset_for(body, profile.and_then(|p| p.offset))
+    {
+        naive += offset;
+    }
+    // `OffsetTime` belongs to `DateTime`, but is usually the same and
+    // better than guessing.
+    let offset = [offset_tag, Tag::OffsetTime]
+        .iter()
+        .find_map(|&t| read_ascii_field(exif, t).map(|v| (t, v)));
+    let dt = match offset {
+        // If an offset tag exists, prefer it rather than the `from_tz`.
+        Some((offset_tag, offset)) => {
+            ResolvedTime::from_local(&date_time_with_offset(&naive, offset_tag, &offset)?, None)
+        }
+        None => {
+            // The clock setting of the body wins over where the photo was taken.
+            let mut gps_without_zone = None;
+            let from_tz = match profile.and_then(|p| p.timezone) {
+                Some(tz) => Some(tz),
+                None => gps_timezone(exif, options).unwrap_or_else(|position| {
+                    gps_without_zone = Some(position);
+                    None
+                }),
+            };
+            let time =
+                resolve_local_time_in(from_tz.or(options.from_tz), &naive, options.dst_policy)
+                    .map_err(|e| DateTimeError::from_local_time_error(tag, &value, e))?;
+            ResolvedTime {
+                gps_without_zone,
+                ..time
+            }
+        }
+    };
+    Ok(Some(dt))
+}
+
+/// Returns the raw value of a date time tag and the local time with the sub-second tag.
+fn read_clock(
+    exif: &Exif,
+    tag: Tag,
+    subsec_tag: Tag,
+) -> Result<Option<(String, NaiveDateTime)>, DateTimeError> {
+    let value = match read_raw_ascii_field(exif, tag) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let mut naive = date_time_as_naive(tag, &value)?;
+    let subsec = read_ascii_field(exif, subsec_tag).filter(|s| is_subsec(s));
+    if let Some(nanos) = subsec.and_then(|s| subsec_as_nanos(&s)) {
+        naive = naive.with_nanosecond(nanos).unwrap_or(naive);
+    }
+    Ok(Some((value, naive)))
+}
+
+/// Returns the time zone at the GPS position if `tz_from_gps` is set.
+///
+/// A position in no known time zone is returned as the error.
+fn gps_timezone(exif: &Exif, options: &DateOptions) -> Result<Option<Tz>, (f64, f64)> {
+    if !options.tz_from_gps {
+        return Ok(None);
+    }
+    match read_gps_position(exif) {
+        Some((latitude, longitude)) => match timezone_at(latitude, longitude) {
+            Some(tz) => Ok(Some(tz)),
+            None => Err((latitude, longitude)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Returns the `GPSLatitude` and `GPSLongitude` in signed degrees.
+fn read_gps_position(exif: &Exif) -> Option<(f64, f64)> {
+    let coordinate = |tag, ref_tag, negative: &str, limit: f64| {
+        let degrees = match exif.get_field(tag, In::PRIMARY)?.value {
+            Value::Rational(ref v) if v.len() == 3 => {
+                v[0].to_f64() + v[1].to_f64() / 60.0 + v[2].to_f64() / 3600.0
+            }
+            _ => return None,
+        };
+        let sign = match read_ascii_field(exif, ref_tag) {
+            Some(ref r) if r == negative => -1.0,
+            _ => 1.0,
+        };
+        Some(sign * degrees).filter(|d| d.abs() <= limit)
+    };
+    let latitude = coordinate(Tag::GPSLatitude, Tag::GPSLatitudeRef, "S", 90.0)?;
+    let longitude = coordinate(Tag::GPSLongitude, Tag::GPSLongitudeRef, "W", 180.0)?;
+    Some((latitude, longitude))
+}
+
+/// Returns the `GPSDateStamp` and `GPSTimeStamp`, which are in UTC.
+fn read_gps_date(exif: &Exif) -> Result<Option<DateTime<Tz>>, DateTimeError> {
+    let (date, time) = match (
+        read_ascii_field(exif, Tag::GPSDateStamp),
+        exif.get_field(Tag::GPSTimeStamp, In::PRIMARY),
+    ) {
+        (Some(date), Some(time)) => (date, time),
+        _ => return Ok(None),
+    };
+    let date = NaiveDate::parse_from_str(&date, "%Y:%m:%d")
+        .map_err(|_| DateTimeError::new(Tag::GPSDateStamp, &date, DateTimeErrorKind::Malformed))?;
+    let seconds = match time.value {
+        Value::Rational(ref v) if v.len() == 3 => {
+            v[0].to_f64() * 3600.0 + v[1].to_f64() * 60.0 + v[2].to_f64()
+        }
+        _ => f64::NAN,
+    };
+    if !(0.0..86400.0).contains(&seconds) {
+        let value = time.display_value().to_string();
+        return Err(DateTimeError::new(
+            Tag::GPSTimeStamp,
+            &value,
+            DateTimeErrorKind::Malformed,
+        ));
+    }
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+    let naive = midnight + Duration::milliseconds((seconds * 1000.0).round() as i64);
+    Ok(Some(UTC.from_utc_datetime(&naive)))
+}
+
+fn is_subsec(s: &str) -> bool {
+    s.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Converts the digits of a fraction of a second, e.g. "05" is 50 milliseconds.
+fn subsec_as_nanos(subsec: &str) -> Option<u32> {
+    let digits: String = subsec.chars().chain("000000000".chars()).take(9).collect();
+    digits.parse().ok()
+}
+
+fn read_ascii_field(exif: &Exif, tag: Tag) -> Option<String> {
+    read_raw_ascii_field(exif, tag)
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+fn read_raw_ascii_field(exif: &Exif, tag: Tag) -> Option<String> {
+    let field = exif.get_field(tag, In::PRIMARY)?;
+    match field.value {
+        Value::Ascii(ref v) => v.first().map(|s| String::from_utf8_lossy(s).to_string()),
+        _ => None,
+    }
+}
+
+fn date_time_as_naive(tag: Tag, value: &str) -> Result<NaiveDateTime, DateTimeError> {
+    // The Exif spec fills unknown dates with blanks, e.g. "    :  :     :  :  ".
+    if value.chars().all(|c| c == ' ' || c == ':') {
+        return Err(DateTimeError::new(tag, value, DateTimeErrorKind::Blank));
+    }
+    NaiveDateTime::parse_from_str(value.trim(), "%Y:%m:%d %H:%M:%S")
+        .map_err(|_| DateTimeError::new(tag, value, DateTimeErrorKind::Malformed))
 }
 
-fn utc_date_time_original_with_offset(dto: &exif::Field, oto: &exif::Field) -> DateTime<Tz> {
-    let dt_str = format!("{}{}", field_as_string(dto), field_as_string(oto));
-    dbg!(&dt_str);
-    return DateTime::parse_from_str(&dt_str, "%Y-%m-%d %H:%M:%S%:z")
-        .unwrap()
-        .with_timezone(&UTC);
+fn date_time_with_offset(
+    naive: &NaiveDateTime,
+    tag: Tag,
+    offset: &str,
+) -> Result<DateTime<FixedOffset>, DateTimeError> {
+    let dt_str = format!("{}{}", naive.format("%Y-%m-%d %H:%M:%S%.f"), offset);
+    DateTime::parse_from_str(&dt_str, "%Y-%m-%d %H:%M:%S%.f%:z")
+        .map_err(|_| DateTimeError::new(tag, offset, DateTimeErrorKind::Malformed))
 }
@@ -0,0 +1,125 @@
+extern crate exif;
+
+use super::camera::CameraId;
+use super::cr3::Cr3Extractor;
+use super::error::Error;
+use super::exif::ExifExtractor;
+use super::heif::HeifExtractor;
+use super::metadata::{DateOptions, Metadata};
+use super::quicktime::QuickTimeExtractor;
+use super::raf::RafExtractor;
+use super::tiff::TiffVariantExtractor;
+use super::x3f::X3fExtractor;
+use exif::Exif;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// The number of leading bytes which are read to detect the format of a file.
+pub const HEAD_LEN: usize = 64;
+
+/// A reader of the capture metadata of a file format.
+pub trait MetadataExtractor: Send + Sync {
+    /// The name of the format, e.g. `X3F`.
+    fn name(&self) -> &'static str;
+
+    /// Returns true if a file which starts with `head`, at most `HEAD_LEN` bytes,
+    /// is in the format.
+    fn matches_magic(&self, head: &[u8]) -> bool;
+
+    /// Returns true if the lowercase extension `lcext` is one of the format.
+    /// It is only asked if no format matches the magic of the file.
+    fn matches_extension(&self, lcext: &str) -> bool;
+
+    fn extract(&self, path: &Path, options: &DateOptions) -> Result<Metadata, Error>;
+
+    /// Reads the Exif of a file with the body which `extract` would find,
+    /// to measure the camera clock. A format without Exif returns `None`.
+    fn read_exif(&self, _path: &Path) -> Result<Option<(CameraId, Exif)>, Error> {
+        Ok(None)
+    }
+}
+
+/// The extractors to pick from by the format of a file.
+///
+/// An extractor registered later is asked first, so that a new format can
+/// take files over from a more generic one.
+pub struct FormatRegistry {
+    extractors: Vec<Box<dyn MetadataExtractor>>,
+}
+
+impl FormatRegistry {
+    /// Returns a registry without any format.
+    pub fn new() -> Self {
+        FormatRegistry {
+            extractors: Vec::new(),
+        }
+    }
+
+    pub fn register(&mut self, extractor: Box<dyn MetadataExtractor>) {
+        self.extractors.insert(0, extractor);
+    }
+
+    /// Returns the extractor for the file, or `None` if no format reads it.
+    ///
+    /// The magic of the file is matched against all the formats before the extension is,
+    /// so that a misnamed file still goes to its format.
+    pub fn find(&self, path: &Path) -> Result<Option<&dyn MetadataExtractor>, Error> {
+        let mut head = Vec::with_capacity(HEAD_LEN);
+        File::open(path).and_then(|file| file.take(HEAD_LEN as u64).read_to_end(&mut head))?;
+        let lcext = path
+            .extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_lowercase();
+        Ok(self
+            .extractors
+            .iter()
+            .find(|e| e.matches_magic(&head))
+            .or_else(|| self.extractors.iter().find(|e| e.matches_extension(&lcext)))
+            .map(|e| e.as_ref()))
+    }
+
+    /// Reads the metadata of a file with its format.
+    ///
+    /// A file in no known format, e.g. a sidecar or `.DS_Store`, is not dated
+    /// by the other date sources either, since it is not a photo.
+    pub fn read_metadata(&self, path: &Path, options: &DateOptions) -> Result<Metadata, Error> {
+        match self.find(path)? {
+            Some(extractor) => extractor.extract(path, options),
+            None => Err(Error::UnknownFormat),
+        }
+    }
+
+    /// Reads the Exif of a file with its format, and the body which took it.
+    pub fn read_exif(&self, path: &Path) -> Result<Option<(CameraId, Exif)>, Error> {
+        match self.find(path)? {
+            Some(extractor) => extractor.read_exif(path),
+            None => Err(Error::UnknownFormat),
+        }
+    }
+}
+
+/// Returns a registry with the built-in formats.
+impl Default for FormatRegistry {
+    fn default() -> Self {
+        let mut registry = FormatRegistry::new();
+        registry.register(Box::new(ExifExtractor));
+        registry.register(Box::new(QuickTimeExtractor));
+        registry.register(Box::new(Cr3Extractor));
+        registry.register(Box::new(RafExtractor));
+        registry.register(Box::new(TiffVariantExtractor));
+        registry.register(Box::new(HeifExtractor));
+        registry.register(Box::new(X3fExtractor));
+        registry
+    }
+}
+
+impl fmt::Debug for FormatRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.extractors.iter().map(|e| e.name()))
+            .finish()
+    }
+}
@@ -0,0 +1,33 @@
+extern crate chrono_tz;
+extern crate tzf_rs;
+
+use chrono_tz::Tz;
+use std::sync::OnceLock;
+use tzf_rs::DefaultFinder;
+
+/// Returns the IANA time zone at the position from the bundled boundary dataset.
+///
+/// The dataset is loaded on the first lookup, which takes a moment.
+pub fn timezone_at(latitude: f64, longitude: f64) -> Option<Tz> {
+    static FINDER: OnceLock<DefaultFinder> = OnceLock::new();
+    let finder = FINDER.get_or_init(DefaultFinder::new);
+    // A zone which is newer than the chrono-tz database cannot be used.
+    finder.get_tz_name(longitude, latitude).parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zones_renamed_since_2019_are_found() {
+        for &(latitude, longitude, name) in &[
+            (50.4501, 30.5234, "Europe/Kyiv"),
+            (64.1814, -51.6941, "America/Nuuk"),
+            (31.6904, -106.4245, "America/Ciudad_Juarez"),
+        ] {
+            let tz = timezone_at(latitude, longitude);
+            assert_eq!(tz.map(|tz| tz.name()), Some(name));
+        }
+    }
+}
@@ -0,0 +1,18 @@
+extern crate sha2;
+
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+/// Returns the SHA-256 digest of the file content as a lowercase hex string.
+pub fn sha256_file(path: &Path) -> Result<String, io::Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hasher
+        .result()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
@@ -0,0 +1,363 @@
+extern crate byteorder;
+extern crate exif;
+
+use super::camera::CameraId;
+use super::error::Error;
+use super::exif::{parse_tiff_exif, read_metadata_with_exif, with_camera_id};
+use super::format::MetadataExtractor;
+use super::isobmff::{
+    brands, find_box, parse_boxes, read_sized_uint, read_top_level_box, BmffBox, BoxError,
+};
+use super::metadata::{DateOptions, Metadata};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
+use exif::Exif;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// The `meta` box holds item descriptions only, so anything larger is broken.
+const MAX_META_LEN: u64 = 16 * 1024 * 1024;
+
+/// The Exif item is a few kilobytes, and a few megabytes with a maker note.
+const MAX_EXIF_LEN: u64 = 16 * 1024 * 1024;
+
+const HEIF_BRANDS: &[&[u8; 4]] = &[
+    b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"mif1", b"msf1", b"avif", b"avis",
+];
+
+/// HEIF images, e.g. the HEIC photos of iPhones, and AVIF images.
+///
+/// kamadak-exif reads HEIF too, but it refuses an Exif item larger than 64 KiB,
+/// which a large maker note exceeds, and files whose compatible brands lack `mif1`.
+pub struct HeifExtractor;
+
+impl MetadataExtractor for HeifExtractor {
+    fn name(&self) -> &'static str {
+        "HEIF"
+    }
+
+    fn matches_magic(&self, head: &[u8]) -> bool {
+        brands(head)
+            .iter()
+            .any(|brand| HEIF_BRANDS.contains(&brand))
+    }
+
+    fn matches_extension(&self, lcext: &str) -> bool {
+        ["heic", "heif", "hif", "avif"].contains(&lcext)
+    }
+
+    fn extract(&self, path: &Path, options: &DateOptions) -> Result<Metadata, Error> {
+        read_heif_metadata(path, options)
+    }
+
+    fn read_exif(&self, path: &Path) -> Result<Option<(CameraId, Exif)>, Error> {
+        Ok(read_heif_exif(path)?.map(with_camera_id))
+    }
+}
+
+pub fn read_heif_metadata(path: &Path, options: &DateOptions) -> Result<Metadata, Error> {
+    let exif = read_heif_exif(path)?;
+    read_metadata_with_exif(exif.as_ref(), path, options)
+}
+
+fn read_heif_exif(path: &Path) -> Result<Option<Exif>, Error> {
+    let file = File::open(path)?;
+    match read_exif_item(&mut BufReader::new(file))? {
+        Some(tiff) => Ok(Some(parse_tiff_exif(tiff)?)),
+        None => Ok(None),
+    }
+}
+
+/// Where the data of an item is, according to the `iloc` box.
+#[derive(Debug)]
+struct ItemLocation {
+    /// 0 for offsets in the file, 1 for offsets in the `idat` box.
+    construction_method: u16,
+    base_offset: u64,
+    /// The offsets and the lengths, where a length of 0 means up to the end.
+    extents: Vec<(u64, u64)>,
+}
+
+/// Reads the TIFF data of the `Exif` item, which the `meta` box describes.
+fn read_exif_item<R: Read + Seek>(reader: &mut R) -> Result<Option<Vec<u8>>, BoxError> {
+    if read_top_level_box(reader, b"ftyp", 4096)?.is_none() {
+        return Err(BoxError::InvalidData("Not a HEIF file"));
+    }
+    let meta = match read_top_level_box(reader, b"meta", MAX_META_LEN)? {
+        Some(meta) => meta,
+        None => return Ok(None),
+    };
+    let (_, _, children) = BmffBox {
+        box_type: *b"meta",
+        data: &meta,
+    }
+    .full_box()?;
+    let boxes = parse_boxes(children)?;
+    let item_id = match find_box(&boxes, b"iinf") {
+        Some(iinf) => find_exif_item_id(&iinf)?,
+        None => None,
+    };
+    let item_id = match item_id {
+        Some(item_id) => item_id,
+        None => return Ok(None),
+    };
+    let location = match find_box(&boxes, b"iloc") {
+        Some(iloc) => find_item_location(&iloc, item_id)?,
+        None => None,
+    };
+    let location = location.ok_or(BoxError::InvalidData("No location of the Exif item"))?;
+    let invalid_extent = || BoxError::InvalidData("Invalid extent of the Exif item");
+    let mut data = Vec::new();
+    for &(offset, length) in location.extents.iter() {
+        let offset = location
+            .base_offset
+            .checked_add(offset)
+            .ok_or_else(invalid_extent)?;
+        match location.construction_method {
+            0 => {
+                let file_len = reader.seek(SeekFrom::End(0))?;
+                let length = if length == 0 {
+                    file_len.saturating_sub(offset)
+                } else {
+                    length
+                };
+                let end = offset.checked_add(length).ok_or_else(invalid_extent)?;
+                if end > file_len || data.len() as u64 + length > MAX_EXIF_LEN {
+                    return Err(invalid_extent());
+                }
+                reader.seek(SeekFrom::Start(offset))?;
+                reader.take(length).read_to_end(&mut data)?;
+            }
+            1 => {
+                let idat = find_box(&boxes, b"idat")
+                    .ok_or(BoxError::InvalidData("No idat box"))?
+                    .data;
+                let start = usize::try_from(offset).map_err(|_| invalid_extent())?;
+                let end = if length == 0 {
+                    idat.len()
+                } else {
+                    usize::try_from(length)
+                        .ok()
+                        .and_then(|length| start.checked_add(length))
+                        .ok_or_else(invalid_extent)?
+                };
+                let extent = idat.get(start..end).ok_or_else(invalid_extent)?;
+                data.extend_from_slice(extent);
+            }
+            _ => return Err(BoxError::InvalidData("Unsupported construction method")),
+        }
+    }
+    // The item starts with the offset of the TIFF header, which usually
+    // follows an `Exif\0\0` marker.
+    let tiff = match data.get(..4) {
+        Some(skip) => data.get(4 + BigEndian::read_u32(skip) as usize..),
+        None => None,
+    };
+    match tiff {
+        Some(tiff) => Ok(Some(tiff.to_vec())),
+        None => Err(BoxError::InvalidData("Invalid Exif item")),
+    }
+}
+
+fn find_exif_item_id(iinf: &BmffBox) -> Result<Option<u32>, BoxError> {
+    let (version, _, data) = iinf.full_box()?;
+    let count_len = if version == 0 { 2 } else { 4 };
+    let entries = data
+        .get(count_len..)
+        .ok_or(BoxError::InvalidData("Truncated iinf box"))?;
+    for infe in parse_boxes(entries)?
+        .iter()
+        .filter(|b| &b.box_type == b"infe")
+    {
+        let (version, _, data) = infe.full_box()?;
+        // Only versions 2 and later have an item type.
+        if version < 2 {
+            continue;
+        }
+        let mut cursor = Cursor::new(data);
+        let item_id = if version == 2 {
+            u32::from(cursor.read_u16::<BigEndian>()?)
+        } else {
+            cursor.read_u32::<BigEndian>()?
+        };
+        let _protection_index = cursor.read_u16::<BigEndian>()?;
+        let mut item_type = [0; 4];
+        cursor.read_exact(&mut item_type)?;
+        if &item_type == b"Exif" {
+            return Ok(Some(item_id));
+        }
+    }
+    Ok(None)
+}
+
+fn find_item_location(iloc: &BmffBox, item_id: u32) -> Result<Option<ItemLocation>, BoxError> {
+    let (version, _, data) = iloc.full_box()?;
+    let mut cursor = Cursor::new(data);
+    let sizes = cursor.read_u16::<BigEndian>()?;
+    let offset_size = usize::from(sizes >> 12);
+    let length_size = usize::from(sizes >> 8 & 0xf);
+    let base_offset_size = usize::from(sizes >> 4 & 0xf);
+    let index_size = match version {
+        1 | 2 => usize::from(sizes & 0xf),
+        _ => 0,
+    };
+    let item_count = match version {
+        0 | 1 => u32::from(cursor.read_u16::<BigEndian>()?),
+        _ => cursor.read_u32::<BigEndian>()?,
+    };
+    for _ in 0..item_count {
+        let id = match version {
+            0 | 1 => u32::from(cursor.read_u16::<BigEndian>()?),
+            _ => cursor.read_u32::<BigEndian>()?,
+        };
+        let construction_method = match version {
+            1 | 2 => cursor.read_u16::<BigEndian>()? & 0xf,
+            _ => 0,
+        };
+        let _data_reference_index = cursor.read_u16::<BigEndian>()?;
+        let base_offset = read_sized_uint(&mut cursor, base_offset_size)?;
+        let extent_count = cursor.read_u16::<BigEndian>()?;
+        let mut extents = Vec::new();
+        for _ in 0..extent_count {
+            let _index = read_sized_uint(&mut cursor, index_size)?;
+            let offset = read_sized_uint(&mut cursor, offset_size)?;
+            let length = read_sized_uint(&mut cursor, length_size)?;
+            extents.push((offset, length));
+        }
+        if id == item_id {
+            return Ok(Some(ItemLocation {
+                construction_method,
+                base_offset,
+                extents,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::isobmff::{bmff_box, full_box};
+    use super::*;
+
+    /// An item of `iloc`: the ID, the construction method, the base offset and the extents.
+    type Item<'a> = (u16, u16, u64, &'a [(u32, u32)]);
+
+    /// An `iloc` box of version 1 with 4 byte offsets and lengths and an 8 byte base offset.
+    fn iloc(items: &[Item]) -> Vec<u8> {
+        let mut data = vec![0x44, 0x80];
+        data.extend_from_slice(&(items.len() as u16).to_be_bytes());
+        for &(id, method, base_offset, extents) in items.iter() {
+            data.extend_from_slice(&id.to_be_bytes());
+            data.extend_from_slice(&method.to_be_bytes());
+            data.extend_from_slice(&0u16.to_be_bytes());
+            data.extend_from_slice(&base_offset.to_be_bytes());
+            data.extend_from_slice(&(extents.len() as u16).to_be_bytes());
+            for &(offset, length) in extents.iter() {
+                data.extend_from_slice(&offset.to_be_bytes());
+                data.extend_from_slice(&length.to_be_bytes());
+            }
+        }
+        full_box(b"iloc", 1, &data)
+    }
+
+    fn location(iloc: &[u8], item_id: u32) -> Result<Option<ItemLocation>, BoxError> {
+        let boxes = parse_boxes(iloc).unwrap();
+        find_item_location(&boxes[0], item_id)
+    }
+
+    /// A HEIF file whose `Exif` item 2 is at the given location.
+    fn heif(iloc: &[u8], idat: &[u8], mdat: &[u8]) -> Vec<u8> {
+        let mut infe = Vec::new();
+        infe.extend(full_box(b"infe", 2, b"\0\x01\0\0hvc1\0"));
+        infe.extend(full_box(b"infe", 2, b"\0\x02\0\0Exif\0"));
+        let mut iinf = 2u16.to_be_bytes().to_vec();
+        iinf.extend(infe);
+        let mut meta = full_box(b"iinf", 0, &iinf);
+        meta.extend_from_slice(iloc);
+        meta.extend(bmff_box(b"idat", idat));
+        let mut file = bmff_box(b"ftyp", b"heic\0\0\0\0mif1heic");
+        file.extend(full_box(b"meta", 0, &meta));
+        file.extend(bmff_box(b"mdat", mdat));
+        file
+    }
+
+    /// An Exif item whose TIFF data is `tiff`.
+    fn exif_item(tiff: &[u8]) -> Vec<u8> {
+        let mut item = 6u32.to_be_bytes().to_vec();
+        item.extend_from_slice(b"Exif\0\0");
+        item.extend_from_slice(tiff);
+        item
+    }
+
+    #[test]
+    fn item_location_is_found_by_id() {
+        let iloc = iloc(&[(1, 0, 0, &[(100, 10)]), (2, 1, 16, &[(4, 20), (40, 0)])]);
+        let found = location(&iloc, 2).unwrap().unwrap();
+        assert_eq!(found.construction_method, 1);
+        assert_eq!(found.base_offset, 16);
+        assert_eq!(found.extents, vec![(4, 20), (40, 0)]);
+        assert!(location(&iloc, 3).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_item_locations_are_rejected() {
+        let iloc = iloc(&[(1, 0, 0, &[(100, 10)]), (2, 0, 0, &[(200, 20)])]);
+        // Cut the last extent, keeping the box header consistent.
+        let mut cut = iloc[..iloc.len() - 4].to_vec();
+        let len = cut.len() as u32;
+        cut[..4].copy_from_slice(&len.to_be_bytes());
+        assert!(location(&cut, 2).is_err());
+        let mut bad_size = iloc.clone();
+        // An offset size of 3 bytes does not exist.
+        bad_size[12] = 0x34;
+        assert!(location(&bad_size, 1).is_err());
+    }
+
+    #[test]
+    fn exif_item_is_read_from_the_file_or_idat() {
+        let item = exif_item(b"II*\0TIFF");
+        // The item is in idat after a few other bytes.
+        let file = heif(
+            &iloc(&[(2, 1, 0, &[(3, item.len() as u32)])]),
+            &[b"pad", &item[..]].concat(),
+            b"",
+        );
+        let tiff = read_exif_item(&mut Cursor::new(&file)).unwrap();
+        assert_eq!(tiff.as_deref(), Some(&b"II*\0TIFF"[..]));
+
+        // The item is split into two extents at the end of the file.
+        let placeholder = heif(&iloc(&[(2, 0, 0, &[(0, 0), (0, 0)])]), b"", &item);
+        let start = (placeholder.len() - item.len()) as u32;
+        let file = heif(
+            &iloc(&[(2, 0, u64::from(start), &[(0, 5), (5, 0)])]),
+            b"",
+            &item,
+        );
+        let tiff = read_exif_item(&mut Cursor::new(&file)).unwrap();
+        assert_eq!(tiff.as_deref(), Some(&b"II*\0TIFF"[..]));
+    }
+
+    #[test]
+    fn extents_beyond_the_data_are_rejected() {
+        let item = exif_item(b"II*\0TIFF");
+        // The base offset and the offset overflow.
+        let file = heif(&iloc(&[(2, 0, u64::MAX, &[(16, 4)])]), b"", &item);
+        assert!(read_exif_item(&mut Cursor::new(&file)).is_err());
+        let file = heif(&iloc(&[(2, 1, u64::MAX, &[(16, 4)])]), &item, b"");
+        assert!(read_exif_item(&mut Cursor::new(&file)).is_err());
+        // The extent runs past the end of the file or of idat.
+        let file = heif(&iloc(&[(2, 0, 0, &[(0, u32::MAX)])]), b"", &item);
+        assert!(read_exif_item(&mut Cursor::new(&file)).is_err());
+        let file = heif(&iloc(&[(2, 1, 0, &[(4, 100)])]), &item, b"");
+        assert!(read_exif_item(&mut Cursor::new(&file)).is_err());
+    }
+
+    #[test]
+    fn files_without_an_exif_item_have_no_exif() {
+        let file = bmff_box(b"ftyp", b"heic\0\0\0\0mif1heic");
+        assert!(read_exif_item(&mut Cursor::new(&file)).unwrap().is_none());
+        assert!(read_exif_item(&mut Cursor::new(b"\0\0\0\x08free")).is_err());
+    }
+}
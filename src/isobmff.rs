@@ -0,0 +1,261 @@
+extern crate byteorder;
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+/// A box of an ISO base media file, which HEIF, MP4, QuickTime and CR3 are made of.
+#[derive(Debug, Clone, Copy)]
+pub struct BmffBox<'a> {
+    pub box_type: [u8; 4],
+    /// The contents after the header.
+    pub data: &'a [u8],
+}
+
+impl<'a> BmffBox<'a> {
+    /// Returns the version, the flags and the rest of the contents of a full box.
+    pub fn full_box(&self) -> Result<(u8, u32, &'a [u8]), BoxError> {
+        if self.data.len() < 4 {
+            return Err(BoxError::InvalidData("Truncated full box"));
+        }
+        let version_and_flags = BigEndian::read_u32(self.data);
+        Ok((
+            (version_and_flags >> 24) as u8,
+            version_and_flags & 0x00ff_ffff,
+            &self.data[4..],
+        ))
+    }
+}
+
+/// Splits the contents of a container box into its boxes.
+pub fn parse_boxes(data: &[u8]) -> Result<Vec<BmffBox<'_>>, BoxError> {
+    let mut boxes = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (box_type, header_len, size) = read_header(&mut Cursor::new(rest), rest.len() as u64)?;
+        boxes.push(BmffBox {
+            box_type,
+            data: &rest[header_len as usize..size as usize],
+        });
+        rest = &rest[size as usize..];
+    }
+    Ok(boxes)
+}
+
+/// Returns the first box of the type.
+pub fn find_box<'a>(boxes: &[BmffBox<'a>], box_type: &[u8; 4]) -> Option<BmffBox<'a>> {
+    boxes.iter().find(|b| &b.box_type == box_type).copied()
+}
+
+/// Reads the contents of the first top level box of the type in a file.
+///
+/// The box must not be longer than `max_len`, since it is read into memory.
+pub fn read_top_level_box<R: Read + Seek>(
+    reader: &mut R,
+    box_type: &[u8; 4],
+    max_len: u64,
+) -> Result<Option<Vec<u8>>, BoxError> {
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    let mut offset = 0;
+    // A few bytes of padding may follow the last box.
+    while offset + 8 <= file_len {
+        reader.seek(SeekFrom::Start(offset))?;
+        let (found, header_len, size) = read_header(reader, file_len - offset)?;
+        if &found == box_type {
+            if size - header_len > max_len {
+                return Err(BoxError::InvalidData("Box too large"));
+            }
+            let mut data = vec![0; (size - header_len) as usize];
+            reader.read_exact(&mut data)?;
+            return Ok(Some(data));
+        }
+        offset += size;
+    }
+    Ok(None)
+}
+
+/// Returns the major and the compatible brands of the `ftyp` box at the start of a file.
+pub fn brands(head: &[u8]) -> Vec<[u8; 4]> {
+    if head.len() < 16 || &head[4..8] != b"ftyp" {
+        return Vec::new();
+    }
+    let end = (BigEndian::read_u32(head) as usize).min(head.len());
+    // The minor version between the major brand and the compatible brands is skipped.
+    let compatible = head.get(16..end).unwrap_or_default();
+    let mut brands = vec![[head[8], head[9], head[10], head[11]]];
+    brands.extend(compatible.chunks_exact(4).map(|b| [b[0], b[1], b[2], b[3]]));
+    brands
+}
+
+/// Reads a big endian unsigned integer of 0, 4 or 8 bytes, as the `iloc` box has.
+pub fn read_sized_uint<R: Read>(reader: &mut R, size: usize) -> Result<u64, BoxError> {
+    match size {
+        0 => Ok(0),
+        4 | 8 => Ok(reader.read_uint::<BigEndian>(size)?),
+        _ => Err(BoxError::InvalidData("Invalid field size")),
+    }
+}
+
+/// Reads a box header and returns the type, the length of the header and the
+/// size of the whole box, which must fit in `available` bytes.
+fn read_header<R: Read>(reader: &mut R, available: u64) -> Result<([u8; 4], u64, u64), BoxError> {
+    let size = reader.read_u32::<BigEndian>()?;
+    let mut box_type = [0; 4];
+    reader.read_exact(&mut box_type)?;
+    let (header_len, size) = match size {
+        // The box extends to the end of its container.
+        0 => (8, available),
+        1 => (16, reader.read_u64::<BigEndian>()?),
+        size => (8, u64::from(size)),
+    };
+    if size < header_len || size > available {
+        return Err(BoxError::InvalidData("Invalid box size"));
+    }
+    Ok((box_type, header_len, size))
+}
+
+#[derive(Debug)]
+pub enum BoxError {
+    Io(io::Error),
+    InvalidData(&'static str),
+}
+
+impl std::fmt::Display for BoxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            BoxError::Io(ref err) => err.fmt(f),
+            BoxError::InvalidData(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl std::error::Error for BoxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            BoxError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BoxError {
+    fn from(err: io::Error) -> BoxError {
+        BoxError::Io(err)
+    }
+}
+
+/// Builds a box with a 32-bit size, for the tests of the formats.
+#[cfg(test)]
+pub(crate) fn bmff_box(box_type: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&(8 + data.len() as u32).to_be_bytes());
+    out.extend_from_slice(box_type);
+    out.extend_from_slice(data);
+    out
+}
+
+/// Builds a full box with the version and no flags.
+#[cfg(test)]
+pub(crate) fn full_box(box_type: &[u8; 4], version: u8, data: &[u8]) -> Vec<u8> {
+    let mut contents = vec![version, 0, 0, 0];
+    contents.extend_from_slice(data);
+    bmff_box(box_type, &contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boxes_are_split_by_their_sizes() {
+        let mut data = bmff_box(b"free", b"abc");
+        // A 64-bit size.
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(b"mdat");
+        data.extend_from_slice(&18u64.to_be_bytes());
+        data.extend_from_slice(b"de");
+        // A size of 0 extends to the end.
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data.extend_from_slice(b"skip");
+        data.extend_from_slice(b"fgh");
+        let boxes = parse_boxes(&data).unwrap();
+        let parsed: Vec<_> = boxes.iter().map(|b| (&b.box_type, b.data)).collect();
+        assert_eq!(
+            parsed,
+            vec![
+                (b"free", &b"abc"[..]),
+                (b"mdat", &b"de"[..]),
+                (b"skip", &b"fgh"[..])
+            ]
+        );
+        assert_eq!(find_box(&boxes, b"mdat").unwrap().data, b"de");
+        assert!(find_box(&boxes, b"moov").is_none());
+    }
+
+    #[test]
+    fn truncated_boxes_are_rejected() {
+        let data = bmff_box(b"free", b"abcd");
+        // The header itself is cut.
+        assert!(parse_boxes(&data[..6]).is_err());
+        // The box is longer than its container.
+        assert!(parse_boxes(&data[..10]).is_err());
+        // The 64-bit size is cut.
+        assert!(parse_boxes(b"\0\0\0\x01mdat\0\0\0").is_err());
+        // A full box without its version and flags.
+        let full = BmffBox {
+            box_type: *b"meta",
+            data: b"\0\0",
+        };
+        assert!(full.full_box().is_err());
+    }
+
+    #[test]
+    fn sizes_smaller_than_the_header_are_rejected() {
+        assert!(parse_boxes(b"\0\0\0\x04free").is_err());
+        let mut data = b"\0\0\0\x01mdat".to_vec();
+        data.extend_from_slice(&8u64.to_be_bytes());
+        assert!(parse_boxes(&data).is_err());
+    }
+
+    #[test]
+    fn top_level_boxes_are_read_within_the_limit() {
+        let mut file = bmff_box(b"ftyp", b"crx \0\0\0\x01crx isom");
+        file.extend(bmff_box(b"moov", b"0123456789"));
+        // A few bytes of padding after the last box.
+        file.extend_from_slice(b"\0\0\0");
+        let mut reader = Cursor::new(&file);
+        assert_eq!(
+            read_top_level_box(&mut reader, b"moov", 10)
+                .unwrap()
+                .unwrap(),
+            b"0123456789"
+        );
+        assert!(read_top_level_box(&mut reader, b"meta", 10)
+            .unwrap()
+            .is_none());
+        assert!(read_top_level_box(&mut reader, b"moov", 9).is_err());
+    }
+
+    #[test]
+    fn oversized_top_level_boxes_are_rejected() {
+        let mut file = bmff_box(b"ftyp", b"isom");
+        // The moov box claims more than the file has.
+        file.extend_from_slice(&0xffff_fff0u32.to_be_bytes());
+        file.extend_from_slice(b"moov0123");
+        assert!(read_top_level_box(&mut Cursor::new(&file), b"moov", u64::MAX).is_err());
+        let mut file = bmff_box(b"ftyp", b"isom");
+        file.extend_from_slice(&1u32.to_be_bytes());
+        file.extend_from_slice(b"moov");
+        file.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(read_top_level_box(&mut Cursor::new(&file), b"moov", u64::MAX).is_err());
+    }
+
+    #[test]
+    fn brands_are_read_from_the_ftyp_box() {
+        let ftyp = bmff_box(b"ftyp", b"heic\0\0\0\0mif1heic");
+        assert_eq!(brands(&ftyp), vec![*b"heic", *b"mif1", *b"heic"]);
+        // Only the part of the box in the head is read.
+        assert_eq!(brands(&ftyp[..20]), vec![*b"heic", *b"mif1"]);
+        assert!(brands(&bmff_box(b"moov", b"heic\0\0\0\0mif1")).is_empty());
+        assert!(brands(b"\0\0\0\x10ftyp").is_empty());
+    }
+}
@@ -0,0 +1,352 @@
+extern crate chrono;
+extern crate serde;
+extern crate serde_json;
+
+use super::hash::sha256_file;
+use super::rename::move_file;
+use chrono::{Local, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, LineWriter, Write};
+use std::path::{self, Path, PathBuf};
+
+/// The directory in the destination which keeps the journals.
+pub const JOURNAL_DIR: &str = ".rename-by-exif";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalAction {
+    Mkdir,
+    Move,
+    Copy,
+    /// A file from before the run, which is replaced, has been moved from the
+    /// source into the backup directory of the journal.
+    Backup,
+}
+
+/// A line of the journal. Paths are stored as absolute paths.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub action: JournalAction,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub source: Option<PathBuf>,
+    pub target: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub hash: Option<String>,
+    pub timestamp: String,
+}
+
+/// An error writing a journal or reverting the run in it.
+#[derive(Debug)]
+pub enum JournalError {
+    /// A file cannot be read, written or moved.
+    Io(PathBuf, io::Error),
+    /// A line of the journal cannot be parsed.
+    Parse(PathBuf, usize, serde_json::Error),
+    /// A file of the run no longer exists.
+    Missing(PathBuf),
+    /// A file of the run has been changed after the run.
+    Changed(PathBuf),
+    /// A file is in the way of one which is put back.
+    Occupied(PathBuf),
+    /// A moved file has no source to be put back to.
+    NoSource(PathBuf),
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JournalError::Io(ref path, ref err) => write!(f, "{}: {}", path.display(), err),
+            JournalError::Parse(ref path, line, ref err) => {
+                write!(f, "{}:{}: {}", path.display(), line, err)
+            }
+            JournalError::Missing(ref path) => write!(f, "{} no longer exists", path.display()),
+            JournalError::Changed(ref path) => {
+                write!(f, "{} has been changed after the run", path.display())
+            }
+            JournalError::Occupied(ref path) => write!(f, "{} already exists", path.display()),
+            JournalError::NoSource(ref path) => write!(f, "No source for {}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for JournalError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            JournalError::Io(_, ref err) => Some(err),
+            JournalError::Parse(_, _, ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Records every change of a run as JSON lines under DESTINATION.
+pub struct Journal {
+    path: PathBuf,
+    writer: LineWriter<File>,
+    backups: usize,
+}
+
+impl Journal {
+    pub fn create(destination: &Path) -> Result<Self, JournalError> {
+        let dir = destination.join(JOURNAL_DIR);
+        fs::create_dir_all(&dir).map_err(|e| JournalError::Io(dir.clone(), e))?;
+        let filename = format!("journal-{}.jsonl", Local::now().format("%Y%m%d-%H%M%S%.3f"));
+        let path = absolute(&dir.join(filename))?;
+        let file = File::create(&path).map_err(|e| JournalError::Io(path.clone(), e))?;
+        Ok(Journal {
+            path,
+            writer: LineWriter::new(file),
+            backups: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn record_mkdir(&mut self, dir: &Path) -> Result<(), JournalError> {
+        self.record(JournalAction::Mkdir, None, dir, None)
+    }
+
+    pub(crate) fn record_transfer(
+        &mut self,
+        action: JournalAction,
+        source: &Path,
+        target: &Path,
+    ) -> Result<(), JournalError> {
+        let hash = sha256_file(target).map_err(|e| JournalError::Io(target.to_path_buf(), e))?;
+        self.record(action, Some(source), target, Some(hash))
+    }
+
+    /// Moves a file which is going to be replaced into the backup directory,
+    /// next to the journal, and returns where it is.
+    pub(crate) fn backup(&mut self, file: &Path) -> Result<PathBuf, JournalError> {
+        let dir = self.path.with_extension("backup");
+        fs::create_dir_all(&dir).map_err(|e| JournalError::Io(dir.clone(), e))?;
+        // Files of the same name in different directories may be replaced.
+        self.backups += 1;
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        let backup = dir.join(format!("{}-{}", self.backups, name));
+        move_file(file, &backup).map_err(|e| JournalError::Io(file.to_path_buf(), e))?;
+        self.record_transfer(JournalAction::Backup, file, &backup)?;
+        Ok(backup)
+    }
+
+    fn record(
+        &mut self,
+        action: JournalAction,
+        source: Option<&Path>,
+        target: &Path,
+        hash: Option<String>,
+    ) -> Result<(), JournalError> {
+        let entry = JournalEntry {
+            action,
+            source: source.map(absolute).transpose()?,
+            target: absolute(target)?,
+            hash,
+            timestamp: Utc::now().to_rfc3339(),
+        };
+        let path = &self.path;
+        let io_error = |e| JournalError::Io(path.clone(), e);
+        let line = serde_json::to_string(&entry).map_err(|e| io_error(e.into()))?;
+        // `LineWriter` flushes every line, so the journal survives an interrupted run.
+        writeln!(self.writer, "{}", line).map_err(io_error)
+    }
+}
+
+pub(crate) fn read_journal(path: &Path) -> Result<Vec<JournalEntry>, JournalError> {
+    let io_error = |e| JournalError::Io(path.to_path_buf(), e);
+    let file = File::open(path).map_err(io_error)?;
+    let mut entries = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(io_error)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .map_err(|e| JournalError::Parse(path.to_path_buf(), i + 1, e))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Reverts the run recorded in the journal.
+///
+/// Every entry is verified before anything is changed, and the whole undo
+/// is refused if a target has been modified or a source path is occupied.
+/// `on_undone` gets a description of every reverted entry.
+pub fn undo<F: FnMut(&str)>(path: &Path, mut on_undone: F) -> Result<(), JournalError> {
+    let entries = read_journal(path)?;
+    // A replaced file is put back after the file which replaced it has been reverted.
+    let replaced: HashSet<&Path> = entries
+        .iter()
+        .filter(|e| e.action == JournalAction::Move || e.action == JournalAction::Copy)
+        .map(|e| e.target.as_path())
+        .collect();
+    for entry in entries.iter() {
+        verify_entry(entry, &replaced)?;
+    }
+    for entry in entries.iter().rev() {
+        if let Some(done) = undo_entry(entry)? {
+            on_undone(&done);
+        }
+    }
+    // The backup directory is empty once every backup is back.
+    let _ = fs::remove_dir(path.with_extension("backup"));
+    Ok(())
+}
+
+fn verify_entry(entry: &JournalEntry, replaced: &HashSet<&Path>) -> Result<(), JournalError> {
+    if entry.action == JournalAction::Mkdir {
+        return Ok(());
+    }
+    let target = &entry.target;
+    if entry.action == JournalAction::Backup && !target.exists() && is_restored(entry) {
+        return Ok(());
+    }
+    if !target.is_file() {
+        return Err(JournalError::Missing(target.clone()));
+    }
+    let hash = sha256_file(target).map_err(|e| JournalError::Io(target.clone(), e))?;
+    if entry.hash.as_ref() != Some(&hash) {
+        return Err(JournalError::Changed(target.clone()));
+    }
+    if let Some(ref source) = entry.source {
+        let occupied = match entry.action {
+            JournalAction::Move => source.exists(),
+            JournalAction::Backup => source.exists() && !replaced.contains(source.as_path()),
+            _ => false,
+        };
+        if occupied {
+            return Err(JournalError::Occupied(source.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if a backup has already been put back, as a failed transfer does.
+fn is_restored(entry: &JournalEntry) -> bool {
+    match entry.source {
+        Some(ref source) if source.is_file() => {
+            sha256_file(source).ok().as_ref() == entry.hash.as_ref()
+        }
+        _ => false,
+    }
+}
+
+/// Returns what has been done, if anything.
+fn undo_entry(entry: &JournalEntry) -> Result<Option<String>, JournalError> {
+    let target = &entry.target;
+    match entry.action {
+        JournalAction::Mkdir => {
+            // Keep the directory if something else has been put there.
+            if fs::remove_dir(target).is_ok() {
+                return Ok(Some(format!("rmdir {}", target.display())));
+            }
+            Ok(None)
+        }
+        JournalAction::Backup if !target.exists() => Ok(None),
+        JournalAction::Move | JournalAction::Backup => {
+            let source = entry
+                .source
+                .as_ref()
+                .ok_or_else(|| JournalError::NoSource(target.clone()))?;
+            if let Some(dir) = source.parent() {
+                fs::create_dir_all(dir).map_err(|e| JournalError::Io(dir.to_path_buf(), e))?;
+            }
+            move_file(target, source).map_err(|e| JournalError::Io(target.clone(), e))?;
+            let verb = match entry.action {
+                JournalAction::Backup => "restore",
+                _ => "move",
+            };
+            Ok(Some(format!(
+                "{} {} -> {}",
+                verb,
+                target.display(),
+                source.display()
+            )))
+        }
+        JournalAction::Copy => {
+            fs::remove_file(target).map_err(|e| JournalError::Io(target.clone(), e))?;
+            Ok(Some(format!("remove {}", target.display())))
+        }
+    }
+}
+
+fn absolute(path: &Path) -> Result<PathBuf, JournalError> {
+    path::absolute(path).map_err(|e| JournalError::Io(path.to_path_buf(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    /// Creates an empty directory for a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rename-by-exif-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Replaces `target` with `source` as a run does.
+    fn replace(journal: &mut Journal, source: &Path, target: &Path) {
+        journal.backup(target).unwrap();
+        move_file(source, target).unwrap();
+        journal
+            .record_transfer(JournalAction::Move, source, target)
+            .unwrap();
+    }
+
+    #[test]
+    fn undo_puts_a_replaced_file_back() {
+        let dir = test_dir("journal-replace");
+        let (source, target) = (dir.join("a.jpg"), dir.join("t.jpg"));
+        fs::write(&source, "new").unwrap();
+        fs::write(&target, "old").unwrap();
+        let mut journal = Journal::create(&dir).unwrap();
+        replace(&mut journal, &source, &target);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+
+        let mut done = Vec::new();
+        undo(journal.path(), |d| done.push(d.to_string())).unwrap();
+        assert_eq!(fs::read_to_string(&source).unwrap(), "new");
+        assert_eq!(fs::read_to_string(&target).unwrap(), "old");
+        assert!(done[1].starts_with("restore "));
+        assert!(!journal.path().with_extension("backup").exists());
+    }
+
+    #[test]
+    fn undo_refuses_a_changed_backup() {
+        let dir = test_dir("journal-changed");
+        let (source, target) = (dir.join("a.jpg"), dir.join("t.jpg"));
+        fs::write(&source, "new").unwrap();
+        fs::write(&target, "old").unwrap();
+        let mut journal = Journal::create(&dir).unwrap();
+        replace(&mut journal, &source, &target);
+        let backup = journal.path().with_extension("backup").join("1-t.jpg");
+        fs::write(&backup, "changed").unwrap();
+        assert!(undo(journal.path(), |_| {}).is_err());
+        // Nothing has been reverted.
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+        assert!(!source.exists());
+    }
+
+    #[test]
+    fn undo_skips_a_backup_which_is_already_back() {
+        let dir = test_dir("journal-restored");
+        let target = dir.join("t.jpg");
+        fs::write(&target, "old").unwrap();
+        let mut journal = Journal::create(&dir).unwrap();
+        // The transfer failed and the replaced file has been put back.
+        let backup = journal.backup(&target).unwrap();
+        move_file(&backup, &target).unwrap();
+        undo(journal.path(), |_| {}).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "old");
+    }
+}
@@ -0,0 +1,85 @@
+//! Reads the capture time of photos and renames them by it.
+//!
+//! Read when a file has been captured:
+//!
+//! ```no_run
+//! use rename_by_exif::{read_capture_time, DateOptions, Error};
+//! use std::path::Path;
+//!
+//! let options = DateOptions::default();
+//! match read_capture_time(Path::new("IMG_0001.JPG"), &options) {
+//!     Ok(Some(time)) => println!("{} ({}, offset {:?})", time.utc, time.source, time.original_offset),
+//!     Ok(None) => println!("no date"),
+//!     Err(Error::DateTime(e)) => println!("bad {}: {:?}", e.field, e.kind),
+//!     Err(e) => println!("{}", e),
+//! }
+//! ```
+//!
+//! Plan the renames of some files into a directory, print the plan and execute it:
+//!
+//! ```no_run
+//! use rename_by_exif::collision::{Collision, CollisionResolver, SerialFormat};
+//! use rename_by_exif::plan::{Action, PlanOptions, Planner};
+//! use rename_by_exif::{Journal, Report};
+//! use std::path::{Path, PathBuf};
+//!
+//! let destination = PathBuf::from("/photos");
+//! let mut options = PlanOptions::new(destination.clone());
+//! options.action = Action::Copy;
+//! options.filename_template = "%Y%m%d-%H%M%S_{model}".parse().unwrap();
+//! options.dirname_format = Some(String::from("%Y/%m"));
+//! let resolver = CollisionResolver::new(Collision::Serial, "_{n}".parse().unwrap());
+//! let mut planner = Planner::new(options, resolver);
+//! let mut report = Report::new();
+//! for path in ["IMG_0001.JPG", "IMG_0002.JPG"].iter() {
+//!     if let Err(e) = planner.add_file(Path::new(path), &mut report) {
+//!         report.add_error(Path::new(path), e.to_string());
+//!     }
+//! }
+//! let plan = planner.into_plan();
+//! let mut journal = Journal::create(&destination).unwrap();
+//! plan.execute(&mut journal, &mut report, false, |step, result| match result {
+//!     Ok(()) => println!("{}", step),
+//!     Err(e) => eprintln!("{}", e),
+//! })
+//! .unwrap();
+//! println!("{} transferred, {} failed", report.transferred, report.errors.len());
+//! ```
+//!
+//! Other formats can be read by registering a `format::MetadataExtractor`.
+
+pub mod camera;
+pub mod collision;
+mod cr3;
+pub mod datesource;
+mod error;
+mod exif;
+pub mod format;
+mod gpstz;
+mod hash;
+mod heif;
+mod isobmff;
+pub mod journal;
+pub mod localtime;
+pub mod metadata;
+pub mod plan;
+mod quicktime;
+mod raf;
+mod rename;
+pub mod report;
+pub mod skew;
+pub mod template;
+mod tiff;
+pub mod walk;
+mod x3f;
+
+pub use self::datesource::DateSource;
+pub use self::error::Error;
+pub use self::exif::{DateTimeError, DateTimeErrorKind};
+pub use self::format::{FormatRegistry, MetadataExtractor};
+pub use self::journal::{Journal, JournalError};
+pub use self::metadata::{
+    read_capture_time, read_file_metadata, CaptureTime, DateOptions, Metadata,
+};
+pub use self::plan::{ExecuteError, Plan, PlanError, PlanOptions, Planner};
+pub use self::report::Report;
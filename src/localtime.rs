@@ -0,0 +1,246 @@
+extern crate chrono;
+extern crate chrono_tz;
+
+use chrono::{
+    DateTime, Duration, FixedOffset, Local, LocalResult, NaiveDateTime, Offset, TimeZone,
+};
+use chrono_tz::{Tz, UTC};
+use std::fmt;
+use std::str::FromStr;
+
+/// How to resolve a local time which is ambiguous or nonexistent because of DST.
+///
+/// - `earliest`: takes the earlier of the repeated hour, rejects a gap.
+/// - `latest`: takes the later of the repeated hour, rejects a gap.
+/// - `reject`: rejects both.
+/// - `shift-forward`: takes the earlier of the repeated hour, and moves a time
+///   in a gap forward by the length of the gap, e.g. 02:30 becomes 03:30.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DstPolicy {
+    #[default]
+    Earliest,
+    Latest,
+    Reject,
+    ShiftForward,
+}
+
+impl FromStr for DstPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "earliest" => Ok(DstPolicy::Earliest),
+            "latest" => Ok(DstPolicy::Latest),
+            "reject" => Ok(DstPolicy::Reject),
+            "shift-forward" => Ok(DstPolicy::ShiftForward),
+            _ => Err(format!("Unknown DST policy: {}", s)),
+        }
+    }
+}
+
+/// What the `DstPolicy` did to a local time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DstAdjustment {
+    TookEarliest,
+    TookLatest,
+    ShiftedForward,
+}
+
+impl fmt::Display for DstAdjustment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DstAdjustment::TookEarliest => write!(f, "ambiguous local time, took the earlier one"),
+            DstAdjustment::TookLatest => write!(f, "ambiguous local time, took the later one"),
+            DstAdjustment::ShiftedForward => {
+                write!(f, "nonexistent local time, shifted forward")
+            }
+        }
+    }
+}
+
+/// A date time converted to UTC.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResolvedTime {
+    pub utc: DateTime<Tz>,
+    /// The offset from UTC of the recorded local time, if it was a local time.
+    pub offset: Option<FixedOffset>,
+    /// What the `DstPolicy` did to get the time, if anything.
+    pub adjustment: Option<DstAdjustment>,
+    /// The GPS position, if it should have given the time zone but is in no known zone.
+    pub gps_without_zone: Option<(f64, f64)>,
+}
+
+impl ResolvedTime {
+    /// A time which has been recorded in UTC, e.g. a GPS time.
+    pub fn from_utc(utc: DateTime<Tz>) -> Self {
+        ResolvedTime {
+            utc,
+            offset: None,
+            adjustment: None,
+            gps_without_zone: None,
+        }
+    }
+
+    /// A local time which has been recorded with its offset.
+    pub fn from_local<T: TimeZone>(dt: &DateTime<T>, adjustment: Option<DstAdjustment>) -> Self {
+        ResolvedTime {
+            utc: dt.with_timezone(&UTC),
+            offset: Some(dt.offset().fix()),
+            adjustment,
+            gps_without_zone: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LocalTimeError {
+    Ambiguous,
+    Nonexistent,
+}
+
+/// Converts a local time in the time zone to UTC according to the policy.
+pub fn resolve_local_time<T: TimeZone>(
+    tz: &T,
+    naive: &NaiveDateTime,
+    policy: DstPolicy,
+) -> Result<ResolvedTime, LocalTimeError> {
+    match (tz.from_local_datetime(naive), policy) {
+        (LocalResult::Single(dt), _) => Ok(ResolvedTime::from_local(&dt, None)),
+        (LocalResult::Ambiguous(_, _), DstPolicy::Reject) => Err(LocalTimeError::Ambiguous),
+        (LocalResult::Ambiguous(_, latest), DstPolicy::Latest) => Ok(ResolvedTime::from_local(
+            &latest,
+            Some(DstAdjustment::TookLatest),
+        )),
+        (LocalResult::Ambiguous(earliest, _), _) => Ok(ResolvedTime::from_local(
+            &earliest,
+            Some(DstAdjustment::TookEarliest),
+        )),
+        (LocalResult::None, DstPolicy::ShiftForward) => {
+            // Interpret the time with the offset in effect before the gap.
+            let before = *naive - Duration::hours(3);
+            let offset = match tz.from_local_datetime(&before).earliest() {
+                Some(dt) => dt.offset().fix(),
+                None => return Err(LocalTimeError::Nonexistent),
+            };
+            let utc = *naive - Duration::seconds(i64::from(offset.local_minus_utc()));
+            Ok(ResolvedTime {
+                utc: UTC.from_utc_datetime(&utc),
+                offset: Some(offset),
+                adjustment: Some(DstAdjustment::ShiftedForward),
+                gps_without_zone: None,
+            })
+        }
+        (LocalResult::None, _) => Err(LocalTimeError::Nonexistent),
+    }
+}
+
+/// Converts a local time in `from_tz`, or in the system time zone if none.
+pub fn resolve_local_time_in(
+    from_tz: Option<Tz>,
+    naive: &NaiveDateTime,
+    policy: DstPolicy,
+) -> Result<ResolvedTime, LocalTimeError> {
+    match from_tz {
+        Some(tz) => resolve_local_time(&tz, naive, policy),
+        None => resolve_local_time(&Local, naive, policy),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::{America, Europe, Pacific};
+
+    /// The expected UTC time, offset in hours and adjustment, or the error.
+    type Expected = Result<(&'static str, i32, Option<DstAdjustment>), LocalTimeError>;
+
+    fn check(tz: Tz, local: &str, cases: &[(DstPolicy, Expected)]) {
+        let naive = NaiveDateTime::parse_from_str(local, "%Y-%m-%d %H:%M").unwrap();
+        for &(policy, ref expected) in cases {
+            let resolved = resolve_local_time(&tz, &naive, policy).map(|time| {
+                let utc = time.utc.naive_utc().format("%Y-%m-%d %H:%M").to_string();
+                let hours = time.offset.unwrap().local_minus_utc() / 3600;
+                (utc, hours, time.adjustment)
+            });
+            let expected =
+                expected.map(|(utc, hours, adjustment)| (utc.to_string(), hours, adjustment));
+            assert_eq!(resolved, expected, "{} {} {:?}", tz, local, policy);
+        }
+    }
+
+    #[test]
+    fn a_single_local_time_needs_no_policy() {
+        let expected = Ok(("2019-07-01 10:00", 2, None));
+        check(
+            Europe::Berlin,
+            "2019-07-01 12:00",
+            &[
+                (DstPolicy::Earliest, expected),
+                (DstPolicy::Latest, expected),
+                (DstPolicy::Reject, expected),
+                (DstPolicy::ShiftForward, expected),
+            ],
+        );
+    }
+
+    #[test]
+    fn a_time_in_the_spring_gap_is_rejected_or_shifted_forward() {
+        // Berlin moves from 02:00 CET to 03:00 CEST, so 02:30 is 03:30 CEST.
+        check(
+            Europe::Berlin,
+            "2019-03-31 02:30",
+            &[
+                (DstPolicy::Earliest, Err(LocalTimeError::Nonexistent)),
+                (DstPolicy::Latest, Err(LocalTimeError::Nonexistent)),
+                (DstPolicy::Reject, Err(LocalTimeError::Nonexistent)),
+                (
+                    DstPolicy::ShiftForward,
+                    Ok(("2019-03-31 01:30", 1, Some(DstAdjustment::ShiftedForward))),
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn a_time_in_the_autumn_overlap_takes_the_policy() {
+        // Berlin repeats 02:00 to 03:00, first in CEST and then in CET.
+        check(
+            Europe::Berlin,
+            "2019-10-27 02:30",
+            &[
+                (
+                    DstPolicy::Earliest,
+                    Ok(("2019-10-27 00:30", 2, Some(DstAdjustment::TookEarliest))),
+                ),
+                (
+                    DstPolicy::Latest,
+                    Ok(("2019-10-27 01:30", 1, Some(DstAdjustment::TookLatest))),
+                ),
+                (DstPolicy::Reject, Err(LocalTimeError::Ambiguous)),
+                (
+                    DstPolicy::ShiftForward,
+                    Ok(("2019-10-27 00:30", 2, Some(DstAdjustment::TookEarliest))),
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn shift_forward_looks_three_hours_back_for_the_offset() {
+        // The gap of São Paulo is at midnight, so the offset is from the day before.
+        check(
+            America::Sao_Paulo,
+            "2018-11-04 00:30",
+            &[(
+                DstPolicy::ShiftForward,
+                Ok(("2018-11-04 03:30", -3, Some(DstAdjustment::ShiftedForward))),
+            )],
+        );
+        // Samoa skipped a whole day, which is longer than the look-back.
+        check(
+            Pacific::Apia,
+            "2011-12-30 12:00",
+            &[(DstPolicy::ShiftForward, Err(LocalTimeError::Nonexistent))],
+        );
+    }
+}
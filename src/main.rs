@@ -1,44 +1,182 @@
-extern crate chrono;
+extern crate chrono_tz;
+extern crate rename_by_exif;
 mod app;
-pub mod exif;
-mod x3f;
+mod config;
 
-use self::app::{app, get_extension_filter, get_timezones};
-use self::exif::read_exif_date_time_original;
-use self::x3f::read_x3f_time;
-use chrono::Local;
-use std::path::Path;
+use self::app::{
+    get_clock_shifts, get_collision_resolver, get_date_sources, get_dst_policy,
+    get_extension_filter, get_filename_template, get_matches, get_references, get_timezones,
+    get_walk_options,
+};
+use rename_by_exif::camera::CameraProfiles;
+use rename_by_exif::format::FormatRegistry;
+use rename_by_exif::journal::{undo, Journal};
+use rename_by_exif::metadata::DateOptions;
+use rename_by_exif::plan::{Action, PlanError, PlanOptions, Planner};
+use rename_by_exif::report::{Report, EXIT_FATAL};
+use rename_by_exif::skew::{format_offset, measure_clocks};
+use rename_by_exif::walk::collect_files;
+use std::path::{Path, PathBuf};
 use std::process;
 
 fn main() {
-    let matches = app().get_matches();
+    let matches = get_matches();
+    if let Some(matches) = matches.subcommand_matches("undo") {
+        let journal = Path::new(matches.value_of("journal").unwrap());
+        if let Err(e) = undo(journal, |done| println!("{}", done)) {
+            eprintln!("Failed to undo: {}", e);
+            process::exit(EXIT_FATAL);
+        }
+        return;
+    }
     let (from_tz, to_tz) = get_timezones(&matches);
     let filer_fn = get_extension_filter(&matches);
-    let sources = matches.values_of("sources").unwrap();
-    for filename in sources {
-        let path = Path::new(&filename);
-        let ext = path.extension().unwrap_or_default().to_string_lossy();
-        let lcext = ext.to_lowercase();
-        if !filer_fn(&lcext) {
-            continue;
-        }
-        let dt = if lcext == "x3f" {
-            read_x3f_time(filename, from_tz)
-        } else {
-            read_exif_date_time_original(filename, from_tz)
-        };
-        match dt {
-            Ok(dt) => match dt {
-                Some(dt) => match to_tz {
-                    Some(tz) => println!("{} -> {}", filename, dt.with_timezone(&tz)),
-                    None => println!("{} -> {}", filename, dt.with_timezone(&Local)),
-                },
-                None => println!("{} -> none", filename),
-            },
+    let destination = Path::new(matches.value_of("destination").unwrap());
+    let mut report = Report::new();
+    let sources = collect_files(
+        matches.values_of("sources").unwrap(),
+        &get_walk_options(&matches),
+        &mut report,
+    );
+    let action = if matches.is_present("copy") {
+        Action::Copy
+    } else {
+        Action::Move
+    };
+    let dry_run = matches.is_present("dry-run");
+    let dirname_format = if matches.is_present("subdir-by-date") {
+        matches.value_of("dirname-format")
+    } else {
+        None
+    };
+    let fail_fast = matches.is_present("fail-fast");
+    for (path, error) in report.errors.iter() {
+        eprintln!("{}: {}", path.display(), error);
+    }
+    if fail_fast && !report.errors.is_empty() {
+        process::exit(EXIT_FATAL);
+    }
+    let files: Vec<(&PathBuf, String)> = sources
+        .iter()
+        .map(|path| {
+            let lcext = path
+                .extension()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_lowercase();
+            (path, lcext)
+        })
+        .filter(|(_, lcext)| filer_fn(lcext))
+        .collect();
+    let cameras = match matches.value_of("camera-profiles") {
+        Some(path) => match CameraProfiles::load(Path::new(path)) {
+            Ok(cameras) => cameras,
             Err(e) => {
+                eprintln!("Failed to read the camera profiles {}: {}", path, e);
+                process::exit(EXIT_FATAL);
+            }
+        },
+        None => Default::default(),
+    };
+    let mut date_options = DateOptions::default();
+    date_options.from_tz = from_tz;
+    date_options.dst_policy = get_dst_policy(&matches);
+    date_options.tz_from_gps = matches.is_present("tz-from-gps");
+    date_options.sources = get_date_sources(&matches);
+    date_options.cameras = cameras;
+    let gps_files = if matches.is_present("shift-from-gps") {
+        Some(files.iter().map(|(path, _)| path.as_path()).collect())
+    } else {
+        None
+    };
+    let formats = FormatRegistry::default();
+    date_options.clock = match measure_clocks(
+        get_clock_shifts(&matches),
+        &get_references(&matches),
+        gps_files,
+        &formats,
+        &date_options,
+    ) {
+        Ok(clock) => clock,
+        Err(e) => {
+            eprintln!("Failed to measure the camera clocks: {}", e);
+            process::exit(EXIT_FATAL);
+        }
+    };
+    for (body, offset) in date_options.clock.measured() {
+        eprintln!("clock of {}: {}", body, format_offset(*offset));
+    }
+    let mut options = PlanOptions::new(destination.to_path_buf());
+    options.date_options = date_options;
+    options.formats = formats;
+    options.to_tz = to_tz;
+    options.action = action;
+    options.filename_template = get_filename_template(&matches);
+    options.dirname_format = dirname_format.map(String::from);
+    let mut planner = Planner::new(options, get_collision_resolver(&matches));
+    for (path, _) in files.iter() {
+        match planner.add_file(path, &mut report) {
+            Ok(()) => {}
+            // Nothing has been changed yet.
+            Err(e @ PlanError::Aborted(_)) => {
                 eprintln!("{}", e);
-                process::exit(1);
+                process::exit(EXIT_FATAL);
+            }
+            Err(e) if fail_fast => {
+                eprintln!("{}: {}", path.display(), e);
+                process::exit(EXIT_FATAL);
+            }
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                report.add_error(path, e.to_string());
             }
         }
     }
+    let plan = planner.into_plan();
+
+    if dry_run {
+        plan.dry_run(&mut report, |step| println!("{}", step));
+        print_summary(&report);
+        process::exit(report.exit_code());
+    }
+    let mut journal = match Journal::create(destination) {
+        Ok(journal) => journal,
+        Err(e) => {
+            eprintln!("Failed to create the journal: {}", e);
+            process::exit(EXIT_FATAL);
+        }
+    };
+    let result = plan.execute(
+        &mut journal,
+        &mut report,
+        fail_fast,
+        |step, result| match result {
+            Ok(()) => println!("{}", step),
+            Err(e) => eprintln!("{}: Failed to {}: {}", step.path().display(), step, e),
+        },
+    );
+    println!("journal: {}", journal.path().display());
+    if result.is_err() {
+        process::exit(EXIT_FATAL);
+    }
+    print_summary(&report);
+    process::exit(report.exit_code());
+}
+
+fn print_summary(report: &Report) {
+    eprintln!(
+        "{} transferred, {} skipped, {} failed",
+        report.transferred,
+        report.skipped,
+        report.errors.len()
+    );
+    for (path, error) in report.errors.iter() {
+        eprintln!("  {}: {}", path.display(), error);
+    }
+    if !report.notes.is_empty() {
+        eprintln!("{} notes", report.notes.len());
+        for (path, note) in report.notes.iter() {
+            eprintln!("  {}: {}", path.display(), note);
+        }
+    }
 }
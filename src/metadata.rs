@@ -0,0 +1,94 @@
+extern crate chrono;
+extern crate chrono_tz;
+
+use super::camera::CameraProfiles;
+use super::datesource::{parse_date_sources, DateSource, DEFAULT_DATE_SOURCES};
+use super::error::Error;
+use super::format::FormatRegistry;
+use super::localtime::{DstAdjustment, DstPolicy, ResolvedTime};
+use super::skew::ClockCorrections;
+use chrono::{DateTime, FixedOffset, Utc};
+use chrono_tz::Tz;
+use std::path::Path;
+
+/// Options to interpret the local date times in the metadata.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct DateOptions {
+    pub from_tz: Option<Tz>,
+    pub dst_policy: DstPolicy,
+    /// Take the time zone from the GPS position before `from_tz`.
+    pub tz_from_gps: bool,
+    /// The date sources to try in order.
+    pub sources: Vec<DateSource>,
+    pub cameras: CameraProfiles,
+    pub clock: ClockCorrections,
+}
+
+impl Default for DateOptions {
+    fn default() -> Self {
+        DateOptions {
+            from_tz: None,
+            dst_policy: DstPolicy::default(),
+            tz_from_gps: false,
+            sources: parse_date_sources(DEFAULT_DATE_SOURCES).unwrap(),
+            cameras: CameraProfiles::default(),
+            clock: ClockCorrections::default(),
+        }
+    }
+}
+
+/// When a file has been captured.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CaptureTime {
+    pub utc: DateTime<Utc>,
+    /// The offset from UTC of the camera clock, if the source records a local time.
+    pub original_offset: Option<FixedOffset>,
+    /// Where the time has been taken from.
+    pub source: DateSource,
+    /// Set if the local time needed the DST policy.
+    pub dst_adjustment: Option<DstAdjustment>,
+    /// The GPS position, if it should have given the time zone but is in no known zone.
+    pub gps_without_zone: Option<(f64, f64)>,
+}
+
+impl CaptureTime {
+    pub fn new(time: ResolvedTime, source: DateSource) -> Self {
+        CaptureTime {
+            utc: time.utc.with_timezone(&Utc),
+            original_offset: time.offset,
+            source,
+            dst_adjustment: time.adjustment,
+            gps_without_zone: time.gps_without_zone,
+        }
+    }
+
+    /// Returns the time as the camera clock showed it, or in UTC if the offset is unknown.
+    pub fn original(&self) -> DateTime<FixedOffset> {
+        let offset = self
+            .original_offset
+            .unwrap_or_else(|| FixedOffset::east_opt(0).unwrap());
+        self.utc.with_timezone(&offset)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metadata {
+    pub capture_time: Option<CaptureTime>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub lens: Option<String>,
+    pub subsec: Option<String>,
+}
+
+/// Reads the metadata of a file with the built-in format for it.
+pub fn read_file_metadata(path: &Path, options: &DateOptions) -> Result<Metadata, Error> {
+    FormatRegistry::default().read_metadata(path, options)
+}
+
+/// Reads when a file has been captured, trying the date sources of the options in order.
+///
+/// Returns `None` if no date source has a date.
+pub fn read_capture_time(path: &Path, options: &DateOptions) -> Result<Option<CaptureTime>, Error> {
+    read_file_metadata(path, options).map(|metadata| metadata.capture_time)
+}
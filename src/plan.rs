@@ -0,0 +1,444 @@
+extern crate chrono_tz;
+
+use super::collision::{CollisionError, CollisionResolver, Resolution};
+use super::datesource::DateSource;
+use super::error::Error;
+use super::format::FormatRegistry;
+use super::hash::sha256_file;
+use super::journal::{Journal, JournalAction, JournalError};
+use super::metadata::DateOptions;
+use super::rename::{build_target_path, copy_file, move_file, to_output_time};
+use super::report::Report;
+use super::template::{FilenameTemplate, TemplateContext, DEFAULT_FILENAME_FORMAT};
+use chrono_tz::Tz;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Move,
+    Copy,
+}
+
+#[derive(Debug)]
+pub enum SkipReason {
+    NoDate,
+    Collision,
+    Duplicate(PathBuf),
+    /// The source already has the target name.
+    InPlace,
+    /// No registered format reads the source.
+    Unsupported,
+}
+
+#[derive(Debug)]
+pub enum Step {
+    CreateDir(PathBuf),
+    Transfer {
+        action: Action,
+        source: PathBuf,
+        target: PathBuf,
+        date_source: Option<DateSource>,
+        /// The target is a file from before the run, which is replaced.
+        replace: bool,
+    },
+    Skip {
+        source: PathBuf,
+        reason: SkipReason,
+    },
+}
+
+/// Every operation of a run, resolved before anything is changed.
+///
+/// A dry run prints the plan and a real run executes the same plan,
+/// so both produce the same output.
+#[derive(Debug, Default)]
+pub struct Plan {
+    steps: Vec<Step>,
+    planned_dirs: HashSet<PathBuf>,
+}
+
+impl Plan {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn add_transfer(
+        &mut self,
+        action: Action,
+        source: &Path,
+        target: PathBuf,
+        date_source: Option<DateSource>,
+        replace: bool,
+    ) {
+        if let Some(dir) = target.parent() {
+            if !dir.as_os_str().is_empty() && !dir.is_dir() && !self.planned_dirs.contains(dir) {
+                self.planned_dirs.insert(dir.to_path_buf());
+                self.steps.push(Step::CreateDir(dir.to_path_buf()));
+            }
+        }
+        self.steps.push(Step::Transfer {
+            action,
+            source: source.to_path_buf(),
+            target,
+            date_source,
+            replace,
+        });
+    }
+
+    pub fn add_skip(&mut self, source: &Path, reason: SkipReason) {
+        self.steps.push(Step::Skip {
+            source: source.to_path_buf(),
+            reason,
+        });
+    }
+
+    /// Counts the steps without executing them, passing each to `on_step`.
+    pub fn dry_run<F: FnMut(&Step)>(&self, report: &mut Report, mut on_step: F) {
+        for step in self.steps.iter() {
+            on_step(step);
+            step.count(report);
+        }
+    }
+
+    /// Executes the plan. A failed step is reported and the rest goes on,
+    /// unless `fail_fast` is set. `on_step` gets every step with its result.
+    pub fn execute<F: FnMut(&Step, Result<(), &ExecuteError>)>(
+        &self,
+        journal: &mut Journal,
+        report: &mut Report,
+        fail_fast: bool,
+        mut on_step: F,
+    ) -> Result<(), ExecuteError> {
+        for step in self.steps.iter() {
+            match step.execute(journal) {
+                Ok(()) => {
+                    on_step(step, Ok(()));
+                    step.count(report);
+                }
+                Err(e) => {
+                    on_step(step, Err(&e));
+                    if fail_fast {
+                        return Err(e);
+                    }
+                    report.add_error(step.path(), format!("Failed to {}: {}", step, e));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An error executing a step of a plan.
+#[derive(Debug)]
+pub enum ExecuteError {
+    /// The file system refused the step.
+    Io(io::Error),
+    /// The step cannot be recorded in the journal.
+    Journal(JournalError),
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExecuteError::Io(ref err) => err.fmt(f),
+            ExecuteError::Journal(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ExecuteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            ExecuteError::Io(ref err) => Some(err),
+            ExecuteError::Journal(ref err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for ExecuteError {
+    fn from(err: io::Error) -> ExecuteError {
+        ExecuteError::Io(err)
+    }
+}
+
+impl From<JournalError> for ExecuteError {
+    fn from(err: JournalError) -> ExecuteError {
+        ExecuteError::Journal(err)
+    }
+}
+
+impl Step {
+    pub fn path(&self) -> &Path {
+        match *self {
+            Step::CreateDir(ref dir) => dir,
+            Step::Transfer { ref source, .. } => source,
+            Step::Skip { ref source, .. } => source,
+        }
+    }
+
+    fn count(&self, report: &mut Report) {
+        match *self {
+            Step::CreateDir(_) => {}
+            Step::Transfer { .. } => report.transferred += 1,
+            Step::Skip { .. } => report.skipped += 1,
+        }
+    }
+
+    fn execute(&self, journal: &mut Journal) -> Result<(), ExecuteError> {
+        match *self {
+            Step::CreateDir(ref dir) => {
+                // Record each directory which is actually created, outermost first.
+                let mut missing: Vec<&Path> = dir.ancestors().take_while(|d| !d.exists()).collect();
+                missing.reverse();
+                fs::create_dir_all(dir)?;
+                for d in missing {
+                    journal.record_mkdir(d)?;
+                }
+                Ok(())
+            }
+            Step::Transfer {
+                action,
+                ref source,
+                ref target,
+                replace,
+                ..
+            } => {
+                // Keep the replaced file, so that `undo` can put it back.
+                let backup = if replace && target.exists() {
+                    Some(journal.backup(target)?)
+                } else {
+                    None
+                };
+                let (result, journal_action) = match action {
+                    Action::Move => (move_file(source, target), JournalAction::Move),
+                    Action::Copy => (copy_file(source, target), JournalAction::Copy),
+                };
+                if let Err(e) = result {
+                    if let Some(backup) = backup {
+                        // Put the replaced file back, which `undo` recognizes.
+                        let _ = move_file(&backup, target);
+                    }
+                    return Err(e.into());
+                }
+                Ok(journal.record_transfer(journal_action, source, target)?)
+            }
+            Step::Skip { .. } => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Step::CreateDir(ref dir) => write!(f, "mkdir {}", dir.display()),
+            Step::Transfer {
+                action,
+                ref source,
+                ref target,
+                date_source,
+                replace,
+            } => {
+                let verb = match action {
+                    Action::Move => "move",
+                    Action::Copy => "copy",
+                };
+                write!(f, "{} {} -> {}", verb, source.display(), target.display())?;
+                let mut notes: Vec<String> = date_source.iter().map(|s| s.to_string()).collect();
+                if replace {
+                    notes.push(String::from("overwrite"));
+                }
+                if notes.is_empty() {
+                    Ok(())
+                } else {
+                    write!(f, " ({})", notes.join(", "))
+                }
+            }
+            Step::Skip {
+                ref source,
+                ref reason,
+            } => match *reason {
+                SkipReason::NoDate => write!(f, "skip {} (no date)", source.display()),
+                SkipReason::Collision => write!(f, "skip {} (collision)", source.display()),
+                SkipReason::InPlace => write!(f, "skip {} (already in place)", source.display()),
+                SkipReason::Unsupported => {
+                    write!(f, "skip {} (unsupported format)", source.display())
+                }
+                SkipReason::Duplicate(ref existing) => write!(
+                    f,
+                    "skip {} (duplicate of {})",
+                    source.display(),
+                    existing.display()
+                ),
+            },
+        }
+    }
+}
+
+/// Options to plan the renames.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct PlanOptions {
+    pub destination: PathBuf,
+    pub date_options: DateOptions,
+    /// The formats to read the metadata with.
+    pub formats: FormatRegistry,
+    /// The time zone of the new names, or the system one if none.
+    pub to_tz: Option<Tz>,
+    pub action: Action,
+    pub filename_template: FilenameTemplate,
+    /// The format of the subdirectories by date, e.g. `%Y/%m`, if any.
+    pub dirname_format: Option<String>,
+}
+
+impl PlanOptions {
+    /// Returns the options to move files into `destination` with the default
+    /// names, in the system time zone and without subdirectories.
+    pub fn new(destination: PathBuf) -> Self {
+        PlanOptions {
+            destination,
+            date_options: DateOptions::default(),
+            formats: FormatRegistry::default(),
+            to_tz: None,
+            action: Action::Move,
+            filename_template: DEFAULT_FILENAME_FORMAT.parse().unwrap(),
+            dirname_format: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PlanError {
+    /// The file cannot be planned, e.g. its metadata is broken.
+    File(Error),
+    /// The `abort` collision strategy found a collision, which ends the run.
+    Aborted(CollisionError),
+}
+
+impl fmt::Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PlanError::File(ref e) => write!(f, "{}", e),
+            PlanError::Aborted(ref e) => write!(f, "Aborted: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PlanError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            PlanError::File(ref err) => Some(err),
+            PlanError::Aborted(_) => None,
+        }
+    }
+}
+
+/// Builds a plan by resolving the target of each source file in turn.
+pub struct Planner {
+    options: PlanOptions,
+    resolver: CollisionResolver,
+    plan: Plan,
+    seq: usize,
+}
+
+impl Planner {
+    pub fn new(options: PlanOptions, resolver: CollisionResolver) -> Self {
+        Planner {
+            options,
+            resolver,
+            plan: Plan::new(),
+            seq: 0,
+        }
+    }
+
+    /// Resolves the target of a source file and adds it to the plan.
+    ///
+    /// A file without a date is planned as a skip.
+    pub fn add_file(&mut self, path: &Path, report: &mut Report) -> Result<(), PlanError> {
+        let options = &self.options;
+        let metadata = match options.formats.read_metadata(path, &options.date_options) {
+            Ok(metadata) => metadata,
+            Err(Error::UnknownFormat) => {
+                self.plan.add_skip(path, SkipReason::Unsupported);
+                return Ok(());
+            }
+            Err(e) => return Err(PlanError::File(e)),
+        };
+        let capture_time = match metadata.capture_time {
+            Some(capture_time) => capture_time,
+            None => {
+                self.plan.add_skip(path, SkipReason::NoDate);
+                return Ok(());
+            }
+        };
+        if let Some(adjustment) = capture_time.dst_adjustment {
+            report.add_note(path, adjustment.to_string());
+        }
+        if let Some((latitude, longitude)) = capture_time.gps_without_zone {
+            report.add_note(
+                path,
+                format!(
+                    "no known time zone at GPS position {:.5}, {:.5}",
+                    latitude, longitude
+                ),
+            );
+        }
+        self.seq += 1;
+        let hash = if options.filename_template.needs_hash() {
+            Some(sha256_file(path).map_err(|e| PlanError::File(e.into()))?)
+        } else {
+            None
+        };
+        let lcext = path
+            .extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_lowercase();
+        let dt = to_output_time(&capture_time.utc, options.to_tz);
+        let new_filename = options.filename_template.render(&TemplateContext {
+            date_time: &dt,
+            metadata: &metadata,
+            source: path,
+            lcext: &lcext,
+            seq: self.seq,
+            hash: hash.as_deref(),
+        });
+        let target = build_target_path(
+            &options.destination,
+            &dt,
+            options.dirname_format.as_deref(),
+            &new_filename,
+        );
+        match self.resolver.resolve(path, target) {
+            Ok(Resolution::Target(target)) => self.plan.add_transfer(
+                options.action,
+                path,
+                target,
+                Some(capture_time.source),
+                false,
+            ),
+            Ok(Resolution::Replace(target)) => self.plan.add_transfer(
+                options.action,
+                path,
+                target,
+                Some(capture_time.source),
+                true,
+            ),
+            Ok(Resolution::Skip) => self.plan.add_skip(path, SkipReason::Collision),
+            Ok(Resolution::InPlace) => self.plan.add_skip(path, SkipReason::InPlace),
+            Ok(Resolution::Duplicate(existing)) => {
+                self.plan.add_skip(path, SkipReason::Duplicate(existing))
+            }
+            Err(e @ CollisionError::Collided { .. }) => return Err(PlanError::Aborted(e)),
+            Err(CollisionError::Io(e)) => return Err(PlanError::File(e.into())),
+        }
+        Ok(())
+    }
+
+    pub fn into_plan(self) -> Plan {
+        self.plan
+    }
+}
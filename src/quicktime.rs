@@ -0,0 +1,486 @@
+extern crate byteorder;
+extern crate chrono;
+
+use super::camera::CameraId;
+use super::datesource::DateSource;
+use super::error::Error;
+use super::exif::{read_metadata, DateTimeError, DateTimeErrorKind};
+use super::format::MetadataExtractor;
+use super::isobmff::{brands, find_box, parse_boxes, read_top_level_box, BmffBox, BoxError};
+use super::localtime::{resolve_local_time_in, ResolvedTime};
+use super::metadata::{DateOptions, Metadata};
+use byteorder::{BigEndian, ByteOrder};
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, TimeZone};
+use chrono_tz::UTC;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::Path;
+
+/// The `moov` box of a long video holds large sample tables, but not this large.
+const MAX_MOOV_LEN: u64 = 64 * 1024 * 1024;
+
+const VIDEO_BRANDS: &[&[u8; 4]] = &[
+    b"qt  ", b"isom", b"iso2", b"mp41", b"mp42", b"avc1", b"M4V ", b"3gp4", b"3gp5", b"3g2a",
+    b"XAVC",
+];
+
+const CREATION_DATE_KEY: &str = "com.apple.quicktime.creationdate";
+const MAKE_KEY: &str = "com.apple.quicktime.make";
+const MODEL_KEY: &str = "com.apple.quicktime.model";
+const DAY_ATOM: &[u8; 4] = b"\xa9day";
+const MAKE_ATOM: &[u8; 4] = b"\xa9mak";
+const MODEL_ATOM: &[u8; 4] = b"\xa9mod";
+
+/// QuickTime and MP4 videos.
+///
+/// The creation date is the `Original` date source, taken from the
+/// `com.apple.quicktime.creationdate` key, the `©day` user data, or the
+/// creation time of the movie or of its first track in this order.
+pub struct QuickTimeExtractor;
+
+impl MetadataExtractor for QuickTimeExtractor {
+    fn name(&self) -> &'static str {
+        "QuickTime"
+    }
+
+    fn matches_magic(&self, head: &[u8]) -> bool {
+        brands(head).iter().any(|brand| VIDEO_BRANDS.contains(&brand))
+            // Old QuickTime movies have no `ftyp` box.
+            || [b"moov", b"mdat", b"wide", b"free", b"skip"]
+                .iter()
+                .any(|&box_type| head.get(4..8) == Some(&box_type[..]))
+    }
+
+    fn matches_extension(&self, lcext: &str) -> bool {
+        ["mov", "qt", "mp4", "m4v", "3gp", "3g2"].contains(&lcext)
+    }
+
+    fn extract(&self, path: &Path, options: &DateOptions) -> Result<Metadata, Error> {
+        read_quicktime_metadata(path, options)
+    }
+}
+
+pub fn read_quicktime_metadata(path: &Path, options: &DateOptions) -> Result<Metadata, Error> {
+    let file = File::open(path)?;
+    let info = read_movie_info(&mut BufReader::new(file))?;
+    let body = CameraId {
+        make: info.make.clone(),
+        model: info.model.clone(),
+        serial: None,
+    };
+    read_metadata(None, &body, path, options, |source| match source {
+        DateSource::Original => read_creation_time(&info, &body, options),
+        _ => Ok(None),
+    })
+    .map_err(|e| e.with_path(path).into())
+}
+
+/// The dates and the camera of a movie.
+#[derive(Debug, Default)]
+struct MovieInfo {
+    /// The `com.apple.quicktime.creationdate` key, a local time with its offset.
+    creation_date: Option<String>,
+    /// The `©day` user data, usually a local time with its offset.
+    day: Option<String>,
+    /// The creation time of the `mvhd` box in seconds since 1904 in UTC.
+    movie_created: Option<u64>,
+    /// The creation time of the `mdhd` box of the first track.
+    media_created: Option<u64>,
+    make: Option<String>,
+    model: Option<String>,
+}
+
+fn read_creation_time(
+    info: &MovieInfo,
+    body: &CameraId,
+    options: &DateOptions,
+) -> Result<Option<ResolvedTime>, DateTimeError> {
+    let profile = options.cameras.find(body);
+    let from_tz = profile.and_then(|p| p.timezone).or(options.from_tz);
+    let mut time = None;
+    // A malformed date string is like a missing one, but is reported if
+    // the movie has no other creation time.
+    let mut malformed = None;
+    let dates = [
+        (CREATION_DATE_KEY, info.creation_date.as_ref()),
+        ("\u{a9}day", info.day.as_ref()),
+    ];
+    for &(field, value) in dates.iter() {
+        if let Some(value) = value {
+            let naive_time = |naive: &NaiveDateTime| {
+                resolve_local_time_in(from_tz, naive, options.dst_policy)
+                    .map_err(|e| DateTimeError::from_local_time_error(field, value, e))
+            };
+            match parse_movie_date(field, value, naive_time) {
+                Ok(Some(t)) => {
+                    time = Some(t);
+                    break;
+                }
+                Ok(None) => {}
+                Err(e) if e.kind == DateTimeErrorKind::Malformed => {
+                    malformed.get_or_insert(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    let time = time.or_else(|| {
+        [info.movie_created, info.media_created]
+            .iter()
+            .flatten()
+            .find_map(|&seconds| seconds_since_1904(seconds))
+            .map(ResolvedTime::from_utc)
+    });
+    if let (None, Some(e)) = (time, malformed) {
+        return Err(e);
+    }
+    let offset = options
+        .clock
+        .offset_for(body, profile.and_then(|p| p.offset));
+    Ok(time.map(|time| match offset {
+        Some(offset) => ResolvedTime {
+            utc: time.utc + offset,
+            ..time
+        },
+        None => time,
+    }))
+}
+
+/// Parses a date like `2021-05-06T07:08:09+0900`, `2021-05-06T07:08:09Z` or `2021-05-06`.
+///
+/// A local time without an offset is converted with `naive_time`. A year
+/// alone, which music files have, is not a date of capture.
+fn parse_movie_date<F>(
+    field: &str,
+    value: &str,
+    naive_time: F,
+) -> Result<Option<ResolvedTime>, DateTimeError>
+where
+    F: Fn(&NaiveDateTime) -> Result<ResolvedTime, DateTimeError>,
+{
+    let value = value.trim();
+    if value.len() == 4 && value.bytes().all(|b| b.is_ascii_digit()) {
+        return Ok(None);
+    }
+    let with_offset = match value.strip_suffix('Z') {
+        Some(v) => format!("{}+0000", v),
+        None => value.to_string(),
+    };
+    for format in ["%Y-%m-%dT%H:%M:%S%.f%z", "%Y-%m-%dT%H:%M:%S%z"].iter() {
+        if let Ok(dt) = DateTime::parse_from_str(&with_offset, format) {
+            return Ok(Some(ResolvedTime::from_local(&dt, None)));
+        }
+    }
+    let naive = ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()
+                .and_then(|d| d.and_hms_opt(0, 0, 0))
+        })
+        .ok_or_else(|| DateTimeError::new(field, value, DateTimeErrorKind::Malformed))?;
+    naive_time(&naive).map(Some)
+}
+
+/// Converts a QuickTime time, where 0 means unknown.
+fn seconds_since_1904(seconds: u64) -> Option<DateTime<chrono_tz::Tz>> {
+    if seconds == 0 {
+        return None;
+    }
+    let epoch = NaiveDate::from_ymd_opt(1904, 1, 1)?.and_hms_opt(0, 0, 0)?;
+    let naive = epoch.checked_add_signed(Duration::seconds(i64::try_from(seconds).ok()?))?;
+    Some(UTC.from_utc_datetime(&naive))
+}
+
+fn read_movie_info<R: Read + Seek>(reader: &mut R) -> Result<MovieInfo, BoxError> {
+    let moov = read_top_level_box(reader, b"moov", MAX_MOOV_LEN)?
+        .ok_or(BoxError::InvalidData("No moov box"))?;
+    let boxes = parse_boxes(&moov)?;
+    let mut info = MovieInfo::default();
+    if let Some(mvhd) = find_box(&boxes, b"mvhd") {
+        info.movie_created = Some(read_creation_seconds(&mvhd)?);
+    }
+    if let Some(trak) = find_box(&boxes, b"trak") {
+        if let Some(mdia) = find_box(&parse_boxes(trak.data)?, b"mdia") {
+            if let Some(mdhd) = find_box(&parse_boxes(mdia.data)?, b"mdhd") {
+                info.media_created = Some(read_creation_seconds(&mdhd)?);
+            }
+        }
+    }
+    if let Some(meta) = find_box(&boxes, b"meta") {
+        read_metadata_items(&meta, &mut info)?;
+    }
+    if let Some(udta) = find_box(&boxes, b"udta") {
+        read_user_data(&udta, &mut info)?;
+    }
+    Ok(info)
+}
+
+/// Reads the creation time of a `mvhd` or `mdhd` box.
+fn read_creation_seconds(header: &BmffBox) -> Result<u64, BoxError> {
+    let (version, _, data) = header.full_box()?;
+    match (version, data.len()) {
+        (1, len) if len >= 8 => Ok(BigEndian::read_u64(data)),
+        (0, len) if len >= 4 => Ok(u64::from(BigEndian::read_u32(data))),
+        _ => Err(BoxError::InvalidData("Invalid movie header")),
+    }
+}
+
+/// Reads the items of a `meta` box, which are named either by a `keys` box as
+/// QuickTime does, or by their box types as iTunes does.
+fn read_metadata_items(meta: &BmffBox, info: &mut MovieInfo) -> Result<(), BoxError> {
+    // The QuickTime `meta` is a plain box, while the ISO one is a full box.
+    let data = if meta.data.get(4..8) == Some(&b"hdlr"[..]) {
+        meta.data
+    } else {
+        meta.full_box()?.2
+    };
+    let boxes = parse_boxes(data)?;
+    let keys = match find_box(&boxes, b"keys") {
+        Some(keys) => read_keys(&keys)?,
+        None => Vec::new(),
+    };
+    let ilst = match find_box(&boxes, b"ilst") {
+        Some(ilst) => ilst,
+        None => return Ok(()),
+    };
+    for item in parse_boxes(ilst.data)? {
+        let value = match read_data_string(&item)? {
+            Some(value) => value,
+            None => continue,
+        };
+        let index = BigEndian::read_u32(&item.box_type) as usize;
+        let key = index.checked_sub(1).and_then(|i| keys.get(i));
+        let field = match key.map(String::as_str) {
+            Some(CREATION_DATE_KEY) => &mut info.creation_date,
+            Some(MAKE_KEY) => &mut info.make,
+            Some(MODEL_KEY) => &mut info.model,
+            Some(_) => continue,
+            None if &item.box_type == DAY_ATOM => &mut info.day,
+            None => continue,
+        };
+        field.get_or_insert(value);
+    }
+    Ok(())
+}
+
+/// Reads the key names of a `keys` box.
+fn read_keys(keys: &BmffBox) -> Result<Vec<String>, BoxError> {
+    let (_, _, data) = keys.full_box()?;
+    // The entries are laid out like boxes, with the namespace as the type.
+    let entries = data
+        .get(4..)
+        .ok_or(BoxError::InvalidData("Truncated keys box"))?;
+    Ok(parse_boxes(entries)?
+        .iter()
+        .map(|key| String::from_utf8_lossy(key.data).to_string())
+        .collect())
+}
+
+/// Reads the UTF-8 value of the `data` box of a metadata item.
+fn read_data_string(item: &BmffBox) -> Result<Option<String>, BoxError> {
+    let data = match find_box(&parse_boxes(item.data)?, b"data") {
+        Some(data) => data.data,
+        None => return Ok(None),
+    };
+    // The type and the locale come before the value.
+    match data.get(..8) {
+        Some(header) if BigEndian::read_u32(header) == 1 => {
+            Ok(Some(String::from_utf8_lossy(&data[8..]).trim().to_string()))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Reads the `udta` box, whose `©` atoms hold a text with its length and
+/// language, and which may have an iTunes `meta` box.
+fn read_user_data(udta: &BmffBox, info: &mut MovieInfo) -> Result<(), BoxError> {
+    for atom in parse_boxes(udta.data)? {
+        let field = match &atom.box_type {
+            b"meta" => {
+                read_metadata_items(&atom, info)?;
+                continue;
+            }
+            DAY_ATOM => &mut info.day,
+            MAKE_ATOM => &mut info.make,
+            MODEL_ATOM => &mut info.model,
+            _ => continue,
+        };
+        let text = match atom.data.get(..4) {
+            Some(header) => {
+                let len = usize::from(BigEndian::read_u16(header));
+                atom.data.get(4..4 + len)
+            }
+            None => None,
+        };
+        if let Some(text) = text {
+            let text = String::from_utf8_lossy(text).trim().to_string();
+            if !text.is_empty() {
+                field.get_or_insert(text);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::isobmff::{bmff_box, full_box};
+    use super::*;
+    use std::io::Cursor;
+
+    fn utc(s: &str) -> DateTime<chrono_tz::Tz> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&UTC)
+    }
+
+    fn parse_in_utc(value: &str) -> Result<Option<ResolvedTime>, DateTimeError> {
+        parse_movie_date("\u{a9}day", value, |naive| {
+            Ok(ResolvedTime::from_utc(UTC.from_utc_datetime(naive)))
+        })
+    }
+
+    /// A metadata item of `ilst` with a UTF-8 value.
+    fn item(box_type: &[u8; 4], value: &str) -> Vec<u8> {
+        let mut data = vec![0, 0, 0, 1, 0, 0, 0, 0];
+        data.extend_from_slice(value.as_bytes());
+        bmff_box(box_type, &bmff_box(b"data", &data))
+    }
+
+    /// A QuickTime `meta` box, whose items are numbered after their `keys`.
+    fn quicktime_meta(items: &[(&str, &str)]) -> Vec<u8> {
+        let mut keys = (items.len() as u32).to_be_bytes().to_vec();
+        let mut ilst = Vec::new();
+        for (i, &(key, value)) in items.iter().enumerate() {
+            keys.extend(bmff_box(b"mdta", key.as_bytes()));
+            ilst.extend(item(&(i as u32 + 1).to_be_bytes(), value));
+        }
+        let mut meta = full_box(b"hdlr", 0, b"\0\0\0\0mdta\0\0\0\0\0\0\0\0\0\0\0\0\0");
+        meta.extend(full_box(b"keys", 0, &keys));
+        meta.extend(bmff_box(b"ilst", &ilst));
+        bmff_box(b"meta", &meta)
+    }
+
+    /// A `©` atom of `udta` with its length and language.
+    fn user_data_text(box_type: &[u8; 4], text: &str) -> Vec<u8> {
+        let mut data = (text.len() as u16).to_be_bytes().to_vec();
+        data.extend_from_slice(&[0x55, 0xc4]);
+        data.extend_from_slice(text.as_bytes());
+        bmff_box(box_type, &data)
+    }
+
+    fn movie(mvhd_seconds: u32, mdhd_seconds: u32, more: &[Vec<u8>]) -> Vec<u8> {
+        let mdhd = full_box(b"mdhd", 0, &mdhd_seconds.to_be_bytes());
+        let trak = bmff_box(b"trak", &bmff_box(b"mdia", &mdhd));
+        let mut moov = full_box(b"mvhd", 0, &mvhd_seconds.to_be_bytes());
+        moov.extend(trak);
+        for b in more {
+            moov.extend_from_slice(b);
+        }
+        let mut file = bmff_box(b"ftyp", b"qt  \0\0\0\0qt  ");
+        file.extend(bmff_box(b"moov", &moov));
+        file
+    }
+
+    #[test]
+    fn movie_dates_are_read_with_their_offsets() {
+        let with_offset = parse_in_utc("2021-05-06T07:08:09+0900").unwrap().unwrap();
+        assert_eq!(with_offset.utc, utc("2021-05-05T22:08:09Z"));
+        assert_eq!(
+            with_offset.offset.map(|o| o.local_minus_utc()),
+            Some(9 * 3600)
+        );
+        let zulu = parse_in_utc("2021-05-06T07:08:09.5Z").unwrap().unwrap();
+        assert_eq!(zulu.utc, utc("2021-05-06T07:08:09.5Z"));
+        let naive = parse_in_utc("2021-05-06 07:08:09").unwrap().unwrap();
+        assert_eq!(naive.utc, utc("2021-05-06T07:08:09Z"));
+        let date = parse_in_utc("2021-05-06").unwrap().unwrap();
+        assert_eq!(date.utc, utc("2021-05-06T00:00:00Z"));
+    }
+
+    #[test]
+    fn a_year_alone_is_no_date_and_garbage_is_malformed() {
+        assert!(parse_in_utc(" 2021 ").unwrap().is_none());
+        let error = parse_in_utc("May 2021").unwrap_err();
+        assert_eq!(error.kind, DateTimeErrorKind::Malformed);
+    }
+
+    #[test]
+    fn quicktime_times_count_from_1904() {
+        assert_eq!(seconds_since_1904(0), None);
+        assert_eq!(
+            seconds_since_1904(2_082_844_800),
+            Some(utc("1970-01-01T00:00:00Z"))
+        );
+        assert_eq!(
+            seconds_since_1904(3_652_778_096),
+            Some(utc("2019-10-01T12:34:56Z"))
+        );
+        assert_eq!(seconds_since_1904(u64::MAX), None);
+    }
+
+    #[test]
+    fn keys_name_the_items_and_udta_holds_the_day() {
+        let file = movie(
+            1,
+            2,
+            &[
+                quicktime_meta(&[
+                    ("com.apple.quicktime.location.ISO6709", "+35.6+139.7/"),
+                    (MAKE_KEY, "Apple"),
+                    (CREATION_DATE_KEY, "2021-05-06T07:08:09+0900"),
+                    (MODEL_KEY, "iPhone 12"),
+                ]),
+                bmff_box(
+                    b"udta",
+                    &[
+                        user_data_text(DAY_ATOM, "2020-01-02T03:04:05Z"),
+                        user_data_text(MAKE_ATOM, "Other"),
+                    ]
+                    .concat(),
+                ),
+            ],
+        );
+        let info = read_movie_info(&mut Cursor::new(file)).unwrap();
+        assert_eq!(info.movie_created, Some(1));
+        assert_eq!(info.media_created, Some(2));
+        assert_eq!(
+            info.creation_date.as_deref(),
+            Some("2021-05-06T07:08:09+0900")
+        );
+        assert_eq!(info.day.as_deref(), Some("2020-01-02T03:04:05Z"));
+        // The keys of `meta` come before the user data.
+        assert_eq!(info.make.as_deref(), Some("Apple"));
+        assert_eq!(info.model.as_deref(), Some("iPhone 12"));
+    }
+
+    #[test]
+    fn itunes_items_are_named_by_their_box_types() {
+        let mut ilst = item(DAY_ATOM, "2020-01-02");
+        ilst.extend(item(b"\xa9nam", "Title"));
+        let mut meta = full_box(b"hdlr", 0, b"\0\0\0\0mdirappl\0\0\0\0\0\0\0\0\0");
+        meta.extend(bmff_box(b"ilst", &ilst));
+        let file = movie(0, 0, &[bmff_box(b"udta", &full_box(b"meta", 0, &meta))]);
+        let info = read_movie_info(&mut Cursor::new(file)).unwrap();
+        assert_eq!(info.day.as_deref(), Some("2020-01-02"));
+        assert_eq!(info.creation_date, None);
+    }
+
+    #[test]
+    fn a_malformed_date_falls_through_to_the_movie_header() {
+        let options = DateOptions::default();
+        let body = CameraId::default();
+        let mut info = MovieInfo {
+            creation_date: Some(String::from("not a date")),
+            movie_created: Some(3_652_778_096),
+            ..Default::default()
+        };
+        let time = read_creation_time(&info, &body, &options).unwrap().unwrap();
+        assert_eq!(time.utc, utc("2019-10-01T12:34:56Z"));
+
+        info.movie_created = None;
+        let error = read_creation_time(&info, &body, &options).unwrap_err();
+        assert_eq!(error.kind, DateTimeErrorKind::Malformed);
+    }
+}
@@ -0,0 +1,175 @@
+extern crate byteorder;
+extern crate exif;
+
+use super::camera::CameraId;
+use super::error::Error;
+use super::exif::{read_camera_id, read_exif_container, read_metadata};
+use super::format::MetadataExtractor;
+use super::metadata::{DateOptions, Metadata};
+use byteorder::{BigEndian, ByteOrder};
+use exif::Exif;
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const RAF_MAGIC: &[u8; 16] = b"FUJIFILMCCD-RAW ";
+
+/// The header up to the offset and the length of the JPEG image.
+const HEADER_LEN: usize = 92;
+
+/// The embedded JPEG is a preview of a few megabytes.
+const MAX_JPEG_LEN: u32 = 64 * 1024 * 1024;
+
+/// Fujifilm RAF RAW images, whose Exif is in the embedded JPEG preview.
+pub struct RafExtractor;
+
+impl MetadataExtractor for RafExtractor {
+    fn name(&self) -> &'static str {
+        "RAF"
+    }
+
+    fn matches_magic(&self, head: &[u8]) -> bool {
+        head.starts_with(RAF_MAGIC)
+    }
+
+    fn matches_extension(&self, lcext: &str) -> bool {
+        lcext == "raf"
+    }
+
+    fn extract(&self, path: &Path, options: &DateOptions) -> Result<Metadata, Error> {
+        read_raf_metadata(path, options)
+    }
+
+    fn read_exif(&self, path: &Path) -> Result<Option<(CameraId, Exif)>, Error> {
+        let (body, exif) = read_raf_exif(path)?;
+        Ok(exif.map(|exif| (body, exif)))
+    }
+}
+
+pub fn read_raf_metadata(path: &Path, options: &DateOptions) -> Result<Metadata, Error> {
+    let (body, exif) = read_raf_exif(path)?;
+    read_metadata(exif.as_ref(), &body, path, options, |_| Ok(None))
+        .map_err(|e| e.with_path(path).into())
+}
+
+/// Reads the Exif of the embedded JPEG, and the body with the header for the fields it lacks.
+fn read_raf_exif(path: &Path) -> Result<(CameraId, Option<Exif>), Error> {
+    let file = File::open(path)?;
+    let mut reader = BufReader::new(file);
+    let mut header = [0; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    if !header.starts_with(RAF_MAGIC) {
+        return Err(Error::InvalidData("Not a RAF file"));
+    }
+    let jpeg = read_jpeg(&mut reader, &header)?;
+    let exif = read_exif_container(&mut Cursor::new(jpeg))?;
+    // Prefer the Exif fields in the JPEG rather than the header.
+    let exif_body = read_camera_id(exif.as_ref());
+    let body = CameraId {
+        make: exif_body.make.or_else(|| Some(String::from("FUJIFILM"))),
+        model: exif_body.model.or_else(|| camera_name(&header)),
+        serial: exif_body.serial,
+    };
+    Ok((body, exif))
+}
+
+/// Reads the embedded JPEG, which the header locates with big endian offset and length.
+fn read_jpeg<R: Read + Seek>(reader: &mut R, header: &[u8]) -> io::Result<Vec<u8>> {
+    let offset = BigEndian::read_u32(&header[84..88]);
+    let length = BigEndian::read_u32(&header[88..92]);
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    if length > MAX_JPEG_LEN || u64::from(offset) + u64::from(length) > file_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid JPEG offset",
+        ));
+    }
+    reader.seek(SeekFrom::Start(u64::from(offset)))?;
+    let mut jpeg = vec![0; length as usize];
+    reader.read_exact(&mut jpeg)?;
+    Ok(jpeg)
+}
+
+/// Returns the camera name, which is padded with NULs.
+fn camera_name(header: &[u8]) -> Option<String> {
+    let name = &header[28..60];
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    let name = String::from_utf8_lossy(&name[..len]).trim().to_string();
+    Some(name).filter(|name| !name.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    /// A JPEG without any Exif.
+    const JPEG: &[u8] = b"\xff\xd8\xff\xd9";
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rename-by-exif-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn header(camera: &str, offset: u32, length: u32) -> Vec<u8> {
+        let mut header = RAF_MAGIC.to_vec();
+        header.extend_from_slice(b"0201FF129502");
+        let mut name = camera.as_bytes().to_vec();
+        name.resize(32, 0);
+        header.extend(name);
+        header.resize(84, 0);
+        header.extend_from_slice(&offset.to_be_bytes());
+        header.extend_from_slice(&length.to_be_bytes());
+        header
+    }
+
+    #[test]
+    fn the_jpeg_is_located_by_the_header() {
+        let mut file = header("X-T4", 100, JPEG.len() as u32);
+        file.resize(100, 0);
+        file.extend_from_slice(JPEG);
+        let jpeg = read_jpeg(&mut Cursor::new(&file), &file[..HEADER_LEN]).unwrap();
+        assert_eq!(jpeg, JPEG);
+    }
+
+    #[test]
+    fn a_jpeg_outside_the_file_is_rejected() {
+        let len = JPEG.len() as u32;
+        for &(offset, length) in &[(HEADER_LEN as u32, len + 1), (u32::MAX, len), (0, u32::MAX)] {
+            let mut file = header("X-T4", offset, length);
+            file.extend_from_slice(JPEG);
+            let error = read_jpeg(&mut Cursor::new(&file), &file[..HEADER_LEN]).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn camera_names_are_trimmed_of_their_padding() {
+        assert_eq!(
+            camera_name(&header("X-T4 ", 0, 0)),
+            Some(String::from("X-T4"))
+        );
+        assert_eq!(camera_name(&header("", 0, 0)), None);
+        let long = "X".repeat(40);
+        assert_eq!(camera_name(&header(&long, 0, 0)), Some("X".repeat(32)));
+    }
+
+    #[test]
+    fn the_header_names_the_body_without_exif() {
+        let dir = test_dir("raf-body");
+        let path = dir.join("DSCF0001.RAF");
+        let mut file = header("X-T4", HEADER_LEN as u32, JPEG.len() as u32);
+        file.extend_from_slice(JPEG);
+        fs::write(&path, file).unwrap();
+        let (body, exif) = read_raf_exif(&path).unwrap();
+        assert_eq!(body.make.as_deref(), Some("FUJIFILM"));
+        assert_eq!(body.model.as_deref(), Some("X-T4"));
+        assert!(exif.is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
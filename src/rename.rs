@@ -0,0 +1,135 @@
+extern crate chrono;
+extern crate chrono_tz;
+
+use super::hash::sha256_file;
+use chrono::{DateTime, FixedOffset, Local, Offset, Utc};
+use chrono_tz::Tz;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+pub fn to_output_time(dt: &DateTime<Utc>, to_tz: Option<Tz>) -> DateTime<FixedOffset> {
+    match to_tz {
+        Some(tz) => {
+            let dt = dt.with_timezone(&tz);
+            dt.with_timezone(&dt.offset().fix())
+        }
+        None => {
+            let dt = dt.with_timezone(&Local);
+            dt.with_timezone(&dt.offset().fix())
+        }
+    }
+}
+
+pub fn build_target_path(
+    destination: &Path,
+    dt: &DateTime<FixedOffset>,
+    dirname_format: Option<&str>,
+    filename: &str,
+) -> PathBuf {
+    let mut target = destination.to_path_buf();
+    if let Some(format) = dirname_format {
+        // Path separators in the format make nested directories, e.g. `%Y/%m/%d`.
+        target.push(dt.format(format).to_string());
+    }
+    target.push(filename);
+    target
+}
+
+pub fn move_file(source: &Path, target: &Path) -> io::Result<()> {
+    match fs::rename(source, target) {
+        Ok(()) => Ok(()),
+        // `rename` cannot move a file across file systems, e.g. from a memory card.
+        // The source is only removed once the copy has been verified.
+        Err(ref e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            copy_file(source, target)?;
+            fs::remove_file(source)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Copies a file through a temporary file next to the target, which replaces
+/// the target only once the copy has been verified.
+pub fn copy_file(source: &Path, target: &Path) -> io::Result<()> {
+    let temp = temp_path(target);
+    let result = fs::copy(source, &temp)
+        .and_then(|_| verify_copy(source, &temp))
+        .and_then(|()| fs::rename(&temp, target));
+    if result.is_err() {
+        // Do not leave a broken copy behind.
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Returns a hidden name in the directory of the target, so that the final
+/// `rename` does not cross file systems.
+fn temp_path(target: &Path) -> PathBuf {
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    target.with_file_name(format!(".{}.{}.tmp", name, process::id()))
+}
+
+fn verify_copy(source: &Path, target: &Path) -> io::Result<()> {
+    let source_len = fs::metadata(source)?.len();
+    let target_len = fs::metadata(target)?.len();
+    if source_len != target_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Size mismatch after copy ({} bytes != {} bytes)",
+                source_len, target_len
+            ),
+        ));
+    }
+    if sha256_file(source)? != sha256_file(target)? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Content hash mismatch after copy",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// Creates an empty directory for a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rename-by-exif-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn copy_replaces_the_target_without_leaving_a_temporary_file() {
+        let dir = test_dir("copy-replace");
+        fs::write(dir.join("a.jpg"), "new").unwrap();
+        fs::write(dir.join("t.jpg"), "old contents").unwrap();
+        copy_file(&dir.join("a.jpg"), &dir.join("t.jpg")).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("t.jpg")).unwrap(), "new");
+        assert_eq!(entries(&dir), vec!["a.jpg", "t.jpg"]);
+    }
+
+    #[test]
+    fn failed_copy_keeps_the_existing_target() {
+        let dir = test_dir("copy-failed");
+        fs::write(dir.join("t.jpg"), "old").unwrap();
+        assert!(copy_file(&dir.join("missing.jpg"), &dir.join("t.jpg")).is_err());
+        assert_eq!(fs::read_to_string(dir.join("t.jpg")).unwrap(), "old");
+        assert_eq!(entries(&dir), vec!["t.jpg"]);
+    }
+}
@@ -0,0 +1,40 @@
+use std::path::{Path, PathBuf};
+
+/// All files were handled.
+pub const EXIT_OK: i32 = 0;
+/// The run was stopped by an error.
+pub const EXIT_FATAL: i32 = 1;
+/// Some files were skipped because of errors.
+pub const EXIT_PARTIAL: i32 = 2;
+
+/// Collects the per-file errors of a run.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub transferred: usize,
+    pub skipped: usize,
+    pub errors: Vec<(PathBuf, String)>,
+    /// Files which were handled but need attention, e.g. DST adjustments.
+    pub notes: Vec<(PathBuf, String)>,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn add_error(&mut self, path: &Path, error: String) {
+        self.errors.push((path.to_path_buf(), error));
+    }
+
+    pub fn add_note(&mut self, path: &Path, note: String) {
+        self.notes.push((path.to_path_buf(), note));
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        if self.errors.is_empty() {
+            EXIT_OK
+        } else {
+            EXIT_PARTIAL
+        }
+    }
+}
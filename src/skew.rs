@@ -0,0 +1,266 @@
+extern crate chrono;
+
+use super::camera::CameraId;
+use super::error::Error;
+use super::exif::{read_camera_clock, read_gps_clock_skew};
+use super::format::FormatRegistry;
+use super::metadata::DateOptions;
+use chrono::{Duration, NaiveDateTime};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// A `--shift` value, `[BODY=]OFFSET`, e.g. `+01:23:10` or `EOS R5=-00:02:05`.
+#[derive(Debug, Clone)]
+pub struct ClockShift {
+    pub body: Option<String>,
+    pub offset: Duration,
+}
+
+impl FromStr for ClockShift {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (body, offset) = match s.rsplit_once('=') {
+            Some((body, offset)) if !body.trim().is_empty() => {
+                (Some(body.trim().to_string()), offset)
+            }
+            Some(_) => return Err(format!("Empty camera body in shift {}", s)),
+            None => (None, s),
+        };
+        Ok(ClockShift {
+            body,
+            offset: parse_offset(offset)?,
+        })
+    }
+}
+
+/// A `--reference` value, `PHOTO=TIME`: a photo of a clock and the time it shows.
+#[derive(Debug, Clone)]
+pub struct Reference {
+    pub path: PathBuf,
+    pub time: NaiveDateTime,
+}
+
+impl FromStr for Reference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, time) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("Expected PHOTO=TIME: {}", s))?;
+        let time = [
+            "%Y-%m-%d %H:%M:%S",
+            "%Y-%m-%dT%H:%M:%S",
+            "%Y:%m:%d %H:%M:%S",
+        ]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(time.trim(), format).ok())
+        .ok_or_else(|| format!("Invalid time of reference {}: {}", path, time))?;
+        Ok(Reference {
+            path: PathBuf::from(path),
+            time,
+        })
+    }
+}
+
+/// The largest offset accepted, 100 years of 365.25 days.
+const MAX_OFFSET_HOURS: u64 = 100 * 8766;
+
+/// Parses an offset like `+01:23:10`, `-00:05` or `01:00:00`.
+pub fn parse_offset(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid offset {}, expected [+-]HH:MM[:SS]", s);
+    let (sign, rest) = match s.trim().as_bytes().first() {
+        Some(b'-') => (-1, &s.trim()[1..]),
+        Some(b'+') => (1, &s.trim()[1..]),
+        _ => (1, s.trim()),
+    };
+    let fields = rest
+        .split(':')
+        .map(|f| f.parse::<u64>().map_err(|_| invalid()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (hours, minutes, seconds) = match fields[..] {
+        [h, m] if m < 60 => (h, m, 0),
+        [h, m, s] if m < 60 && s < 60 => (h, m, s),
+        _ => return Err(invalid()),
+    };
+    if hours > MAX_OFFSET_HOURS {
+        return Err(format!("Offset {} is more than 100 years", s));
+    }
+    let seconds = (hours * 3600 + minutes * 60 + seconds) as i64;
+    Ok(Duration::seconds(sign * seconds))
+}
+
+/// Formats an offset like `+01:23:10`.
+pub fn format_offset(offset: Duration) -> String {
+    let seconds = offset.num_seconds();
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let seconds = seconds.abs();
+    format!(
+        "{}{:02}:{:02}:{:02}",
+        sign,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// The corrections of the camera clocks, applied to the Exif dates before renaming.
+///
+/// A shift for the body wins over the offset of its camera profile, then over
+/// a measured offset, and then over a shift for all bodies.
+#[derive(Debug, Clone, Default)]
+pub struct ClockCorrections {
+    shifts: Vec<ClockShift>,
+    measured: Vec<(CameraId, Duration)>,
+}
+
+impl ClockCorrections {
+    pub fn new(shifts: Vec<ClockShift>) -> Self {
+        ClockCorrections {
+            shifts,
+            measured: Vec::new(),
+        }
+    }
+
+    /// Adds an offset measured for the body, unless it already has one.
+    pub fn add_measured(&mut self, body: CameraId, offset: Duration) {
+        if !self.measured.iter().any(|(b, _)| *b == body) {
+            self.measured.push((body, offset));
+        }
+    }
+
+    pub fn measured(&self) -> &[(CameraId, Duration)] {
+        &self.measured
+    }
+
+    pub fn offset_for(&self, body: &CameraId, configured: Option<Duration>) -> Option<Duration> {
+        let shift = |keyed: bool| {
+            self.shifts
+                .iter()
+                .find(|s| match s.body {
+                    Some(ref key) => keyed && body.matches(key),
+                    None => !keyed,
+                })
+                .map(|s| s.offset)
+        };
+        shift(true)
+            .or(configured)
+            .or_else(|| {
+                self.measured
+                    .iter()
+                    .find(|(b, _)| b == body)
+                    .map(|&(_, offset)| offset)
+            })
+            .or_else(|| shift(false))
+    }
+}
+
+/// An error measuring the clock of a body from a reference photo.
+#[derive(Debug)]
+pub enum ClockError {
+    /// The photo cannot be read.
+    File(PathBuf, Error),
+    /// The photo has no `DateTimeOriginal`.
+    NoDate(PathBuf),
+}
+
+impl fmt::Display for ClockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClockError::File(ref path, ref err) => write!(f, "{}: {}", path.display(), err),
+            ClockError::NoDate(ref path) => {
+                write!(f, "{}: No DateTimeOriginal", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ClockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            ClockError::File(_, ref err) => Some(err),
+            ClockError::NoDate(_) => None,
+        }
+    }
+}
+
+/// Builds the corrections from the shifts, the reference photos, and the GPS
+/// times of `gps_files` if any, which are read with `formats`.
+pub fn measure_clocks(
+    shifts: Vec<ClockShift>,
+    references: &[Reference],
+    gps_files: Option<Vec<&Path>>,
+    formats: &FormatRegistry,
+    options: &DateOptions,
+) -> Result<ClockCorrections, ClockError> {
+    let mut corrections = ClockCorrections::new(shifts);
+    for reference in references.iter() {
+        let (body, clock) = read_camera_clock(formats, &reference.path)?;
+        corrections.add_measured(body, reference.time - clock);
+    }
+    if let Some(files) = gps_files {
+        for (body, skew) in measure_gps_skews(files, formats, options) {
+            corrections.add_measured(body, skew);
+        }
+    }
+    Ok(corrections)
+}
+
+/// Measures the clock offset of each body from the files which have a GPS time.
+///
+/// The median per body is used, since a GPS fix can be older than the shot.
+/// Files which cannot be read are left to the main pass to report.
+fn measure_gps_skews<'a, I>(
+    files: I,
+    formats: &FormatRegistry,
+    options: &DateOptions,
+) -> Vec<(CameraId, Duration)>
+where
+    I: IntoIterator<Item = &'a Path>,
+{
+    let mut skews: HashMap<CameraId, Vec<Duration>> = HashMap::new();
+    for path in files {
+        if let Ok(Some((body, skew))) = read_gps_clock_skew(formats, path, options) {
+            skews.entry(body).or_default().push(skew);
+        }
+    }
+    let mut medians: Vec<(CameraId, Duration)> = skews
+        .into_iter()
+        .map(|(body, mut skews)| {
+            skews.sort();
+            let median = skews[skews.len() / 2];
+            (body, Duration::seconds(median.num_seconds()))
+        })
+        .collect();
+    medians.sort_by_key(|(body, _)| body.to_string());
+    medians
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_offset_reads_hours_minutes_and_seconds() {
+        assert_eq!(parse_offset("+01:23:10"), Ok(Duration::seconds(5_000 - 10)));
+        assert_eq!(parse_offset("-00:05"), Ok(Duration::minutes(-5)));
+        assert_eq!(parse_offset("876600:00"), Ok(Duration::hours(876_600)));
+        for s in &["01", "01:60", "01:00:60", "01:-5", "+-01:00", "x:00"] {
+            assert!(parse_offset(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn parse_offset_rejects_more_than_100_years() {
+        for s in &[
+            "876601:00",
+            "3000000000:00",
+            "-3000000000000:00",
+            "99999999999999999999:00",
+        ] {
+            assert!(parse_offset(s).is_err(), "{}", s);
+        }
+    }
+}
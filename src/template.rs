@@ -0,0 +1,294 @@
+extern crate chrono;
+
+use super::metadata::Metadata;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, FixedOffset};
+use std::path::{Component, Path};
+use std::str::FromStr;
+
+pub const DEFAULT_FILENAME_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+const DEFAULT_SEQ_WIDTH: usize = 4;
+const DEFAULT_HASH_LENGTH: usize = 8;
+
+/// A filename template which mixes strftime fields with `{...}` placeholders.
+///
+/// Supported placeholders are `{make}`, `{model}`, `{lens}`, `{stem}`, `{ext}`,
+/// `{subsec}`, `{seq}` and `{hash}`. `{seq:N}` pads the sequence number to N digits
+/// and `{hash:N}` takes the first N characters of the SHA-256 digest.
+/// Use `{{` and `}}` for literal braces.
+///
+/// `{subsec}` is the raw `SubSecTimeOriginal`, which is also included in the
+/// date time for the `%.3f` style strftime fields.
+#[derive(Debug)]
+pub struct FilenameTemplate {
+    parts: Vec<TemplatePart>,
+}
+
+#[derive(Debug)]
+enum TemplatePart {
+    Strftime(String),
+    Placeholder(Placeholder),
+}
+
+#[derive(Debug, PartialEq)]
+enum Placeholder {
+    Make,
+    Model,
+    Lens,
+    Stem,
+    Ext,
+    SubSec,
+    Seq(usize),
+    Hash(usize),
+}
+
+pub struct TemplateContext<'a> {
+    pub date_time: &'a DateTime<FixedOffset>,
+    pub metadata: &'a Metadata,
+    pub source: &'a Path,
+    pub lcext: &'a str,
+    pub seq: usize,
+    pub hash: Option<&'a str>,
+}
+
+impl FilenameTemplate {
+    pub fn needs_hash(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, TemplatePart::Placeholder(Placeholder::Hash(_))))
+    }
+
+    fn has_ext(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, TemplatePart::Placeholder(Placeholder::Ext)))
+    }
+
+    /// Renders the filename. The lowercase extension is appended unless `{ext}` is used.
+    pub fn render(&self, ctx: &TemplateContext) -> String {
+        let mut filename = String::new();
+        for part in self.parts.iter() {
+            match part {
+                TemplatePart::Strftime(format) => {
+                    filename.push_str(&ctx.date_time.format(format).to_string())
+                }
+                TemplatePart::Placeholder(placeholder) => {
+                    filename.push_str(&sanitize(&render_placeholder(placeholder, ctx)))
+                }
+            }
+        }
+        if !self.has_ext() && !ctx.lcext.is_empty() {
+            filename.push('.');
+            filename.push_str(ctx.lcext);
+        }
+        filename
+    }
+}
+
+impl FromStr for FilenameTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // The placeholders are sanitized, so the format alone decides the directories.
+        check_relative(s)?;
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("Unclosed placeholder {{{}", name)),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        parts.push(TemplatePart::Strftime(check_items(&literal)?));
+                        literal = String::new();
+                    }
+                    parts.push(TemplatePart::Placeholder(parse_placeholder(&name)?));
+                }
+                '}' => return Err(String::from("Unmatched } (use }} for a literal brace)")),
+                _ => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Strftime(check_items(&literal)?));
+        }
+        if parts.is_empty() {
+            return Err(String::from("Empty filename format"));
+        }
+        Ok(FilenameTemplate { parts })
+    }
+}
+
+/// Verifies that the given strftime format has no invalid specifiers, and
+/// that the path it makes stays in the destination.
+pub fn check_strftime(format: &str) -> Result<String, String> {
+    check_relative(format)?;
+    check_items(format)
+}
+
+fn check_items(format: &str) -> Result<String, String> {
+    if StrftimeItems::new(format).any(|item| item == Item::Error) {
+        return Err(format!("Invalid strftime format: {}", format));
+    }
+    Ok(String::from(format))
+}
+
+/// Rejects an absolute path or a `..`, which would leave the destination.
+fn check_relative(format: &str) -> Result<(), String> {
+    let escapes = Path::new(format).components().any(|c| match c {
+        Component::Normal(_) | Component::CurDir => false,
+        Component::RootDir | Component::Prefix(_) | Component::ParentDir => true,
+    });
+    if escapes {
+        return Err(format!("Format leaves the destination: {}", format));
+    }
+    Ok(())
+}
+
+fn parse_placeholder(name: &str) -> Result<Placeholder, String> {
+    let (key, arg) = match name.find(':') {
+        Some(pos) => (&name[..pos], Some(&name[pos + 1..])),
+        None => (name, None),
+    };
+    let width = |default: usize| match arg {
+        None => Ok(default),
+        Some(arg) => arg
+            .parse::<usize>()
+            .ok()
+            .filter(|n| *n > 0)
+            .ok_or_else(|| format!("Invalid width in placeholder {{{}}}", name)),
+    };
+    let placeholder = match key {
+        "seq" => Placeholder::Seq(width(DEFAULT_SEQ_WIDTH)?),
+        "hash" => Placeholder::Hash(width(DEFAULT_HASH_LENGTH)?),
+        _ if arg.is_some() => return Err(format!("Unknown placeholder {{{}}}", name)),
+        "make" => Placeholder::Make,
+        "model" => Placeholder::Model,
+        "lens" => Placeholder::Lens,
+        "stem" => Placeholder::Stem,
+        "ext" => Placeholder::Ext,
+        "subsec" => Placeholder::SubSec,
+        _ => return Err(format!("Unknown placeholder {{{}}}", name)),
+    };
+    Ok(placeholder)
+}
+
+fn render_placeholder(placeholder: &Placeholder, ctx: &TemplateContext) -> String {
+    let or_unknown =
+        |value: &Option<String>| value.clone().unwrap_or_else(|| String::from("unknown"));
+    match *placeholder {
+        Placeholder::Make => or_unknown(&ctx.metadata.make),
+        Placeholder::Model => or_unknown(&ctx.metadata.model),
+        Placeholder::Lens => or_unknown(&ctx.metadata.lens),
+        Placeholder::Stem => ctx
+            .source
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string(),
+        Placeholder::Ext => String::from(ctx.lcext),
+        Placeholder::SubSec => ctx
+            .metadata
+            .subsec
+            .clone()
+            .unwrap_or_else(|| String::from("0")),
+        Placeholder::Seq(width) => format!("{:0width$}", ctx.seq, width = width),
+        Placeholder::Hash(length) => ctx
+            .hash
+            .map(|h| h.chars().take(length).collect())
+            .unwrap_or_default(),
+    }
+}
+
+/// Replaces characters which cannot be a part of a filename.
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(format: &str, seq: usize) -> String {
+        let template: FilenameTemplate = format.parse().unwrap();
+        let date_time = DateTime::parse_from_rfc3339("2019-10-01T12:34:56+09:00").unwrap();
+        let metadata = Metadata {
+            make: Some(String::from("Canon")),
+            model: Some(String::from("EOS R5")),
+            lens: Some(String::from("RF24-105mm F4/L")),
+            ..Default::default()
+        };
+        template.render(&TemplateContext {
+            date_time: &date_time,
+            metadata: &metadata,
+            source: Path::new("/photos/IMG_0001.JPG"),
+            lcext: "jpg",
+            seq,
+            hash: Some("0123456789abcdef"),
+        })
+    }
+
+    #[test]
+    fn double_braces_are_literal() {
+        assert_eq!(render("{{%Y}}-{{{model}}}", 1), "{2019}-{EOS R5}.jpg");
+    }
+
+    #[test]
+    fn unknown_placeholders_are_rejected() {
+        assert!("%Y-{iso}".parse::<FilenameTemplate>().is_err());
+        assert!("%Y-{make:3}".parse::<FilenameTemplate>().is_err());
+        assert!("%Y-{make".parse::<FilenameTemplate>().is_err());
+        assert!("%Y-make}".parse::<FilenameTemplate>().is_err());
+    }
+
+    #[test]
+    fn seq_is_padded_to_its_width() {
+        assert_eq!(render("{seq}", 7), "0007.jpg");
+        assert_eq!(render("{seq:2}", 7), "07.jpg");
+        assert_eq!(render("{seq:2}", 123), "123.jpg");
+        assert!("{seq:0}".parse::<FilenameTemplate>().is_err());
+        assert!("{seq:x}".parse::<FilenameTemplate>().is_err());
+    }
+
+    #[test]
+    fn placeholders_are_sanitized_and_ext_is_appended_once() {
+        assert_eq!(
+            render("%Y%m%d_{make}_{stem}_{hash:4}", 1),
+            "20191001_Canon_IMG_0001_0123.jpg"
+        );
+        assert_eq!(render("%H%M_{lens}.{ext}", 1), "1234_RF24-105mm F4_L.jpg");
+    }
+
+    #[test]
+    fn formats_which_leave_the_destination_are_rejected() {
+        for format in &["/%Y", "%Y/../%m", "..", "./../%Y"] {
+            assert!(check_strftime(format).is_err(), "{}", format);
+            assert!(format.parse::<FilenameTemplate>().is_err(), "{}", format);
+        }
+        assert!("{make}/../%Y".parse::<FilenameTemplate>().is_err());
+        assert!(check_strftime("./%Y/%m..%d").is_ok());
+        assert_eq!(render("{make}/%Y", 1), "Canon/2019.jpg");
+    }
+}
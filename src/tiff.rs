@@ -0,0 +1,146 @@
+extern crate exif;
+
+use super::camera::CameraId;
+use super::error::Error;
+use super::exif::{parse_tiff_exif, read_metadata_with_exif, with_camera_id};
+use super::format::MetadataExtractor;
+use super::metadata::{DateOptions, Metadata};
+use exif::Exif;
+use std::fs;
+use std::path::Path;
+
+/// The nonstandard signatures of TIFF based RAW formats, with the standard
+/// signature of the same byte order.
+const SIGNATURES: &[(&[u8; 4], &[u8; 4])] = &[
+    // Panasonic RW2 and Leica RWL.
+    (b"IIU\0", b"II*\0"),
+    // Olympus ORF.
+    (b"IIRO", b"II*\0"),
+    (b"IIRS", b"II*\0"),
+    (b"MMOR", b"MM\0*"),
+];
+
+/// RAW formats which are TIFF except for the signature in the header:
+/// Panasonic RW2 and Olympus ORF.
+pub struct TiffVariantExtractor;
+
+impl MetadataExtractor for TiffVariantExtractor {
+    fn name(&self) -> &'static str {
+        "RW2/ORF"
+    }
+
+    fn matches_magic(&self, head: &[u8]) -> bool {
+        standard_signature(head).is_some()
+    }
+
+    fn matches_extension(&self, lcext: &str) -> bool {
+        ["rw2", "rwl", "orf"].contains(&lcext)
+    }
+
+    fn extract(&self, path: &Path, options: &DateOptions) -> Result<Metadata, Error> {
+        read_tiff_variant_metadata(path, options)
+    }
+
+    fn read_exif(&self, path: &Path) -> Result<Option<(CameraId, Exif)>, Error> {
+        Ok(Some(with_camera_id(read_tiff_variant_exif(path)?)))
+    }
+}
+
+pub fn read_tiff_variant_metadata(path: &Path, options: &DateOptions) -> Result<Metadata, Error> {
+    let exif = read_tiff_variant_exif(path)?;
+    read_metadata_with_exif(Some(&exif), path, options)
+}
+
+fn read_tiff_variant_exif(path: &Path) -> Result<Exif, Error> {
+    let mut data = fs::read(path)?;
+    let signature =
+        standard_signature(&data).ok_or(Error::InvalidData("Unknown TIFF signature"))?;
+    // The offsets are as in TIFF, so the standard signature is all it takes.
+    data[..4].copy_from_slice(signature);
+    parse_tiff_exif(data)
+}
+
+fn standard_signature(head: &[u8]) -> Option<&'static [u8; 4]> {
+    SIGNATURES
+        .iter()
+        .find(|(variant, _)| head.starts_with(&variant[..]))
+        .map(|&(_, standard)| standard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rename-by-exif-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A TIFF with the signature and an IFD of a `Model` of three letters.
+    fn tiff(signature: &[u8; 4], model: &[u8; 3]) -> Vec<u8> {
+        let big_endian = signature.starts_with(b"MM");
+        let u16_bytes = |v: u16| {
+            if big_endian {
+                v.to_be_bytes()
+            } else {
+                v.to_le_bytes()
+            }
+        };
+        let u32_bytes = |v: u32| {
+            if big_endian {
+                v.to_be_bytes()
+            } else {
+                v.to_le_bytes()
+            }
+        };
+        let mut data = signature.to_vec();
+        data.extend_from_slice(&u32_bytes(8));
+        data.extend_from_slice(&u16_bytes(1));
+        // `Model`, ASCII of 4 bytes in the entry.
+        data.extend_from_slice(&u16_bytes(0x0110));
+        data.extend_from_slice(&u16_bytes(2));
+        data.extend_from_slice(&u32_bytes(4));
+        data.extend_from_slice(model);
+        data.push(0);
+        data.extend_from_slice(&u32_bytes(0));
+        data
+    }
+
+    #[test]
+    fn variant_signatures_map_to_the_standard_ones() {
+        assert_eq!(standard_signature(b"IIU\0\x08\0\0\0"), Some(b"II*\0"));
+        assert_eq!(standard_signature(b"IIRO"), Some(b"II*\0"));
+        assert_eq!(standard_signature(b"IIRS"), Some(b"II*\0"));
+        assert_eq!(standard_signature(b"MMOR"), Some(b"MM\0*"));
+        assert_eq!(standard_signature(b"II*\0"), None);
+        assert_eq!(standard_signature(b"IIU"), None);
+    }
+
+    #[test]
+    fn variants_are_read_as_tiff() {
+        let dir = test_dir("tiff-variants");
+        for &(name, signature, model) in &[
+            ("a.rw2", b"IIU\0", b"GH6"),
+            ("b.orf", b"IIRO", b"E-5"),
+            ("c.orf", b"MMOR", b"E-1"),
+        ] {
+            let path = dir.join(name);
+            fs::write(&path, tiff(signature, model)).unwrap();
+            let (body, _) = TiffVariantExtractor.read_exif(&path).unwrap().unwrap();
+            let model = String::from_utf8_lossy(model).to_string();
+            assert_eq!(body.model, Some(model), "{}", name);
+        }
+        let path = dir.join("d.orf");
+        fs::write(&path, tiff(b"II*\0", b"E-1")).unwrap();
+        assert!(matches!(
+            read_tiff_variant_exif(&path),
+            Err(Error::InvalidData(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
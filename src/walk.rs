@@ -0,0 +1,78 @@
+extern crate walkdir;
+
+use super::journal::JOURNAL_DIR;
+use super::report::Report;
+use std::path::{Path, PathBuf};
+use walkdir::{DirEntry, WalkDir};
+
+#[derive(Debug, Default)]
+#[non_exhaustive]
+pub struct WalkOptions {
+    pub recursive: bool,
+    pub max_depth: Option<usize>,
+    pub follow_links: bool,
+    pub skip_hidden: bool,
+}
+
+/// Expands the given sources into a list of files.
+///
+/// A directory source yields its direct children, or its whole tree when
+/// `recursive` is set. A file source is returned as is. The journals of
+/// earlier runs are never collected, even if hidden files are.
+///
+/// An entry which cannot be read, e.g. a broken symbolic link, is added to
+/// the report and the walk goes on.
+pub fn collect_files<I, P>(sources: I, options: &WalkOptions, report: &mut Report) -> Vec<PathBuf>
+where
+    I: IntoIterator<Item = P>,
+    P: AsRef<Path>,
+{
+    let max_depth = match (options.recursive, options.max_depth) {
+        (false, _) => 1,
+        (true, Some(depth)) => depth,
+        (true, None) => usize::MAX,
+    };
+    let mut files = Vec::new();
+    for source in sources {
+        let source = source.as_ref();
+        if !source.is_dir() {
+            files.push(source.to_path_buf());
+            continue;
+        }
+        let walker = WalkDir::new(source)
+            .max_depth(max_depth)
+            .follow_links(options.follow_links)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+            .into_iter()
+            .filter_entry(|entry| {
+                !(is_journal_dir(entry) || options.skip_hidden && is_hidden(entry))
+            });
+        for entry in walker {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    // The IO error does not repeat the path, unlike the walk error.
+                    let error = match e.io_error() {
+                        Some(err) => err.to_string(),
+                        None => e.to_string(),
+                    };
+                    report.add_error(e.path().unwrap_or(source), error);
+                    continue;
+                }
+            };
+            if entry.file_type().is_file() {
+                files.push(entry.into_path());
+            }
+        }
+    }
+    files
+}
+
+fn is_journal_dir(entry: &DirEntry) -> bool {
+    entry.file_type().is_dir() && entry.file_name() == JOURNAL_DIR
+}
+
+fn is_hidden(entry: &DirEntry) -> bool {
+    // The source directory itself is never skipped.
+    entry.depth() > 0 && entry.file_name().to_string_lossy().starts_with('.')
+}
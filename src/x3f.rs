@@ -3,29 +3,65 @@ extern crate chrono;
 extern crate derivative;
 extern crate exif;
 
-use super::exif::read_date_time_original_as_utc;
+use super::camera::CameraId;
+use super::datesource::DateSource;
+use super::error::Error;
+use super::exif::{read_camera_id, read_metadata, DateTimeError};
+use super::format::MetadataExtractor;
+use super::localtime::ResolvedTime;
+use super::metadata::{DateOptions, Metadata};
 use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
-use chrono::{DateTime, TimeZone, Utc};
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use chrono_tz::{Tz, UTC};
 use derivative::Derivative;
-use exif::Reader;
+use exif::{Exif, Reader};
 use std::cmp::Ordering;
 use std::default::Default;
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::{self, BufReader, SeekFrom};
+use std::io::{self, BufReader, Cursor, SeekFrom};
+use std::path::Path;
 
-pub fn read_x3f_time(filename: &str, from_tz: Option<Tz>) -> Result<Option<DateTime<Tz>>, String> {
-    let file = File::open(filename).map_err(|e| e.to_string())?;
-    let reader = X3fReader::new(BufReader::new(file), from_tz).map_err(|e| e.to_string())?;
-    Ok(reader.get_taken_datetime())
+/// The Sigma X3F RAW format.
+pub struct X3fExtractor;
+
+impl MetadataExtractor for X3fExtractor {
+    fn name(&self) -> &'static str {
+        "X3F"
+    }
+
+    fn matches_magic(&self, head: &[u8]) -> bool {
+        head.starts_with(b"FOVb")
+    }
+
+    fn matches_extension(&self, lcext: &str) -> bool {
+        lcext == "x3f"
+    }
+
+    fn extract(&self, path: &Path, options: &DateOptions) -> Result<Metadata, Error> {
+        read_x3f_metadata(path, options)
+    }
+
+    fn read_exif(&self, path: &Path) -> Result<Option<(CameraId, Exif)>, Error> {
+        let file = File::open(path)?;
+        let mut reader = X3fReader::new(BufReader::new(file))?;
+        let body = reader.get_camera_id();
+        Ok(reader.exif.take().map(|exif| (body, exif)))
+    }
+}
+
+pub fn read_x3f_metadata(path: &Path, options: &DateOptions) -> Result<Metadata, Error> {
+    let file = File::open(path)?;
+    let reader = X3fReader::new(BufReader::new(file))?;
+    reader
+        .get_metadata(path, options)
+        .map_err(|e| e.with_path(path).into())
 }
 
 struct X3fReader<R: Read + Seek> {
     inner: R,
     properties: Vec<X3fProperty>,
-    exif_datetime: Option<DateTime<Tz>>,
-    from_tz: Option<Tz>,
+    exif: Option<Exif>,
 }
 
 #[derive(Debug)]
@@ -66,12 +102,11 @@ impl X3fImage {
 }
 
 impl<R: Read + Seek> X3fReader<R> {
-    pub fn new(inner: R, from_tz: Option<Tz>) -> Result<Self, X3fError> {
+    pub fn new(inner: R) -> Result<Self, X3fError> {
         let mut reader = X3fReader {
             inner,
             properties: Default::default(),
-            exif_datetime: None,
-            from_tz,
+            exif: None,
         };
         reader.read()?;
         Ok(reader)
@@ -85,27 +120,55 @@ impl<R: Read + Seek> X3fReader<R> {
             .map(|p| &p.value)
     }
 
-    fn get_taken_datetime(&self) -> Option<DateTime<Tz>> {
-        // Prefer Exif::DateTimeOrigial rather than PROP::TIME.
-        match self.exif_datetime {
-            Some(dt) => Some(dt),
-            None => match self.get_property("TIME") {
-                Some(time_str) => {
-                    // Since the time zone of the Sigma camera's internal clock is UTC,
-                    // there may be a time difference from the user's perception.
-                    let timestamp = time_str.parse::<i64>().unwrap();
-                    Some(Utc.timestamp(timestamp, 0).with_timezone(&UTC))
-                }
-                None => None,
-            },
+    fn get_metadata(&self, path: &Path, options: &DateOptions) -> Result<Metadata, DateTimeError> {
+        let prop = |name| self.get_property(name).cloned();
+        let body = self.get_camera_id();
+        let mut metadata =
+            read_metadata(
+                self.exif.as_ref(),
+                &body,
+                path,
+                options,
+                |source| match source {
+                    DateSource::Original => Ok(self
+                        .get_taken_datetime(&body, options)
+                        .map(ResolvedTime::from_utc)),
+                    _ => Ok(None),
+                },
+            )?;
+        metadata.lens = metadata.lens.or_else(|| prop("LENSMODEL"));
+        Ok(metadata)
+    }
+
+    fn get_camera_id(&self) -> CameraId {
+        // Prefer the Exif fields in the thumbnail rather than the PROP section.
+        let prop = |name| self.get_property(name).cloned();
+        let exif_body = read_camera_id(self.exif.as_ref());
+        CameraId {
+            make: exif_body.make.or_else(|| prop("CAMMANUF")),
+            model: exif_body.model.or_else(|| prop("CAMMODEL")),
+            serial: exif_body.serial.or_else(|| prop("CAMSERIAL")),
         }
     }
 
+    fn get_taken_datetime(&self, body: &CameraId, options: &DateOptions) -> Option<DateTime<Tz>> {
+        let time_str = self.get_property("TIME")?;
+        // Since the time zone of the Sigma camera's internal clock is UTC,
+        // there may be a time difference from the user's perception.
+        let timestamp = time_str.parse::<i64>().ok()?;
+        let utc = Utc.timestamp_opt(timestamp, 0).single()?;
+        let configured = options.cameras.find(body).and_then(|p| p.offset);
+        let offset = options
+            .clock
+            .offset_for(body, configured)
+            .unwrap_or_else(Duration::zero);
+        Some(utc.with_timezone(&UTC) + offset)
+    }
+
     fn read(&mut self) -> Result<(), X3fError> {
         self.check_identifier()?;
 
         let dir_entries = self.read_directory_entries()?;
-        dbg!(&dir_entries);
 
         for entry in dir_entries.iter() {
             let offset = entry.offset as u64;
@@ -115,11 +178,8 @@ impl<R: Read + Seek> X3fReader<R> {
                 "IMAG" => { /* UNSUPPORTED */ }
                 "IMA2" => {
                     let image = self.read_image(offset, length)?;
-                    dbg!(&image);
                     if image.is_jpeg_thumbnail() {
-                        if let Some(utc) = self.read_datetime_from_thumbnail(&image) {
-                            self.exif_datetime = Some(utc);
-                        }
+                        self.exif = read_exif_from_thumbnail(&image);
                     }
                 }
                 "PROP" => self.properties = self.read_property_list(offset)?,
@@ -136,14 +196,12 @@ impl<R: Read + Seek> X3fReader<R> {
         // Verify the identifier.
         let mut buf = [0; 4];
         self.inner.read_exact(&mut buf)?;
-        if buf.cmp(&b"FOVb") != Ordering::Equal {
+        if buf.cmp(b"FOVb") != Ordering::Equal {
             return Err(X3fError::InvalidData("Not a X3F (FOVb) file"));
         }
 
-        // Read the version of X3F.
-        let version = self.read_u32()?;
-        let fovb_version_str = format!("{:08x}", version);
-        dbg!(fovb_version_str);
+        // Skip the version of X3F, since every version is read the same way.
+        self.read_u32()?;
 
         Ok(())
     }
@@ -151,20 +209,17 @@ impl<R: Read + Seek> X3fReader<R> {
     fn check_directory(&mut self) -> Result<u32, X3fError> {
         // Read the offset of the directory section and go there.
         let dir_offset = self.read_directory_offset()?;
-        dbg!(dir_offset);
         self.seek_to(dir_offset)?;
 
         // Verify the section identifier.
         let mut buf = [0; 4];
         self.inner.read_exact(&mut buf)?;
-        if buf.cmp(&b"SECd") != Ordering::Equal {
+        if buf.cmp(b"SECd") != Ordering::Equal {
             return Err(X3fError::InvalidData("SECd not found"));
         }
 
         // Verify the section version.
         let version = self.read_u32()?;
-        let secd_version_str = format!("{:08x}", version);
-        dbg!(secd_version_str);
         if version != 0x20000 {
             return Err(X3fError::InvalidData("Unsupported SECd version"));
         }
@@ -176,7 +231,6 @@ impl<R: Read + Seek> X3fReader<R> {
 
     fn read_directory_entries(&mut self) -> Result<Vec<X3fDirectoryEntry>, X3fError> {
         let num_directory_entries = self.check_directory()?;
-        dbg!(num_directory_entries);
 
         let mut entries = Vec::new();
         for _ in 0..num_directory_entries {
@@ -201,16 +255,6 @@ impl<R: Read + Seek> X3fReader<R> {
         Ok(offset as u64)
     }
 
-    fn read_datetime_from_thumbnail(&self, image: &X3fImage) -> Option<DateTime<Tz>> {
-        match Reader::new(&mut BufReader::new(image.data.as_slice())) {
-            Ok(reader) => read_date_time_original_as_utc(&reader, self.from_tz),
-            Err(e) => {
-                dbg!(e);
-                None
-            }
-        }
-    }
-
     fn read_image(&mut self, offset: u64, length: u64) -> Result<X3fImage, X3fError> {
         const IMAGE_HEADER_SIZE: usize = 28;
 
@@ -246,14 +290,12 @@ impl<R: Read + Seek> X3fReader<R> {
         // Verify the section identifiers.
         let mut buf = [0; 4];
         self.inner.read_exact(&mut buf)?;
-        if buf.cmp(&b"SECi") != Ordering::Equal {
+        if buf.cmp(b"SECi") != Ordering::Equal {
             return Err(X3fError::InvalidData("SECi not found"));
         }
 
         // Verify the section version.
         let version = self.read_u32()?;
-        let seci_version_str = format!("{:08x}", version);
-        dbg!(seci_version_str);
         if version != 0x20000 {
             return Err(X3fError::InvalidData("Unsupported SECi version"));
         }
@@ -270,7 +312,6 @@ impl<R: Read + Seek> X3fReader<R> {
         let character_encoding = self.read_u32()?;
         self.seek_by(4)?; // skip reserved
         let total_length = self.read_u32()?;
-        dbg!(num_entries, character_encoding, total_length);
         if character_encoding != 0 {
             return Err(X3fError::InvalidData("Unsupported SECp character encoding"));
         }
@@ -278,7 +319,6 @@ impl<R: Read + Seek> X3fReader<R> {
         // Read properties.
         let entries = self.read_property_entries(num_entries)?;
         let props = self.read_properties(&entries, total_length as usize)?;
-        dbg!(&entries, &props);
 
         Ok(props)
     }
@@ -287,14 +327,12 @@ impl<R: Read + Seek> X3fReader<R> {
         // Verify the section identifiers.
         let mut buf = [0; 4];
         self.inner.read_exact(&mut buf)?;
-        if buf.cmp(&b"SECp") != Ordering::Equal {
+        if buf.cmp(b"SECp") != Ordering::Equal {
             return Err(X3fError::InvalidData("SECp not found"));
         }
 
         // Verify the section version.
         let version = self.read_u32()?;
-        let secp_version_str = format!("{:08x}", version);
-        dbg!(secp_version_str);
         if version != 0x20000 {
             return Err(X3fError::InvalidData("Unsupported SECp version"));
         }
@@ -320,21 +358,21 @@ impl<R: Read + Seek> X3fReader<R> {
 
     fn read_properties(
         &mut self,
-        entries: &Vec<X3fPropertyEntry>,
+        entries: &[X3fPropertyEntry],
         num_characters: usize,
     ) -> Result<Vec<X3fProperty>, io::Error> {
         // Read whole properties as bytes and convert it to string.
         let src_vec = self.read_bytes(num_characters * 2)?;
         let src = src_vec.as_slice();
         let mut dst_vec = vec_with_length(num_characters);
-        let mut dst = dst_vec.as_mut_slice();
-        LittleEndian::read_u16_into(&src, &mut dst);
+        let dst = dst_vec.as_mut_slice();
+        LittleEndian::read_u16_into(src, dst);
 
         // Make a property list.
         let mut props = Vec::new();
         for entry in entries.iter() {
-            let name = extract_utf16_string(&dst, entry.name_offset);
-            let value = extract_utf16_string(&dst, entry.value_offset);
+            let name = extract_utf16_string(dst, entry.name_offset);
+            let value = extract_utf16_string(dst, entry.value_offset);
             props.push(X3fProperty { name, value });
         }
         Ok(props)
@@ -342,7 +380,7 @@ impl<R: Read + Seek> X3fReader<R> {
 
     fn read_bytes(&mut self, length: usize) -> Result<Vec<u8>, io::Error> {
         let mut buf = vec_with_length(length);
-        self.inner.read_exact(&mut buf.as_mut_slice())?;
+        self.inner.read_exact(buf.as_mut_slice())?;
         Ok(buf)
     }
 
@@ -363,10 +401,15 @@ impl<R: Read + Seek> X3fReader<R> {
 }
 
 #[inline]
-fn vec_with_length<T>(length: usize) -> Vec<T> {
-    let mut v = Vec::with_capacity(length);
-    unsafe { v.set_len(length) }
-    v
+fn vec_with_length<T: Clone + Default>(length: usize) -> Vec<T> {
+    vec![T::default(); length]
+}
+
+fn read_exif_from_thumbnail(image: &X3fImage) -> Option<Exif> {
+    // The PROP section is used instead if the thumbnail has no readable Exif.
+    Reader::new()
+        .read_from_container(&mut Cursor::new(image.data.as_slice()))
+        .ok()
 }
 
 #[inline]
@@ -386,19 +429,12 @@ impl std::fmt::Display for X3fError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match *self {
             X3fError::Io(ref err) => err.fmt(f),
-            X3fError::InvalidData(ref s) => write!(f, "{}", *s),
+            X3fError::InvalidData(s) => write!(f, "{}", s),
         }
     }
 }
 
 impl std::error::Error for X3fError {
-    fn description(&self) -> &str {
-        match *self {
-            X3fError::Io(ref err) => err.description(),
-            X3fError::InvalidData(ref s) => *s,
-        }
-    }
-
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match *self {
             X3fError::Io(ref err) => Some(err),
@@ -407,6 +443,15 @@ impl std::error::Error for X3fError {
     }
 }
 
+impl From<X3fError> for Error {
+    fn from(err: X3fError) -> Error {
+        match err {
+            X3fError::Io(err) => Error::Io(err),
+            X3fError::InvalidData(s) => Error::InvalidData(s),
+        }
+    }
+}
+
 impl From<io::Error> for X3fError {
     fn from(err: io::Error) -> X3fError {
         X3fError::Io(err)